@@ -0,0 +1,289 @@
+use crate::health;
+use crate::metrics;
+use crate::overlay;
+use crate::redemption::recent_redemptions;
+use crate::shutdown::COORDINATOR;
+use crate::sound::{player, EventKind, Player};
+use axum::extract::{Path, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use constant_time_eq::constant_time_eq;
+use serde_json::{json, Value};
+use std::env;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+#[derive(Clone)]
+struct ApiState {
+    player: &'static Player,
+    token: Arc<String>,
+}
+
+/// Rejects requests without `Authorization: Bearer <CONTROL_API_TOKEN>`.
+async fn require_token(
+    State(state): State<ApiState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(token)
+            if constant_time_eq(token.as_bytes(), state.token.as_bytes()) =>
+        {
+            next.run(request).await
+        }
+        _ => (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")])
+            .into_response(),
+    }
+}
+
+async fn list_sounds(State(state): State<ApiState>) -> Json<Vec<String>> {
+    Json(state.player.sound_names())
+}
+
+/// Starts a sound in the background and answers right away.
+async fn play_sound(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Value>), StatusCode> {
+    let sound = state
+        .player
+        .find_sound(&name)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .to_string();
+    let guard = COORDINATOR
+        .accept_event()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let player = state.player;
+    let name = sound.clone();
+    tokio::task::spawn_blocking(move || {
+        player.play("control api", &name, EventKind::Api);
+        drop(guard);
+    });
+    Ok((StatusCode::ACCEPTED, Json(json!({ "sound": sound }))))
+}
+
+async fn skip(State(state): State<ApiState>) -> Json<Value> {
+    Json(json!({ "skipped": state.player.skip_current() }))
+}
+
+async fn stop(State(state): State<ApiState>) -> Json<Value> {
+    Json(json!({ "stopped": state.player.stop_all() }))
+}
+
+async fn pause(State(state): State<ApiState>) -> Json<Value> {
+    state.player.set_paused(true);
+    Json(json!({ "paused": true }))
+}
+
+async fn resume(State(state): State<ApiState>) -> Json<Value> {
+    state.player.set_paused(false);
+    Json(json!({ "paused": false }))
+}
+
+/// Events waiting to be handled and the clips playing right now.
+async fn queue(State(state): State<ApiState>) -> Json<Value> {
+    Json(json!({
+        "paused": state.player.is_paused(),
+        "pending_events": COORDINATOR.in_flight(),
+        "playing": state.player.active_sounds(),
+    }))
+}
+
+async fn redemptions() -> Json<Value> {
+    Json(json!(recent_redemptions()))
+}
+
+/// Prometheus metrics in the text exposition format.
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+/// Routes of the control API, all behind the bearer token.
+fn api_router(player: &'static Player, token: String) -> Router {
+    let state = ApiState {
+        player,
+        token: Arc::new(token),
+    };
+    Router::new()
+        .route("/api/sounds", get(list_sounds))
+        .route("/api/sounds/{name}/play", post(play_sound))
+        .route("/api/skip", post(skip))
+        .route("/api/stop", post(stop))
+        .route("/api/pause", post(pause))
+        .route("/api/resume", post(resume))
+        .route("/api/queue", get(queue))
+        .route("/api/redemptions", get(redemptions))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_token,
+        ))
+        .with_state(state)
+}
+
+/// Everything served on `BIND_ADDRESS`: the overlay, the metrics, the health
+/// probes, and the control API if a token is configured. Metrics and probes
+/// need no token so that scrapers and orchestrators can reach them.
+pub fn router(player: &'static Player, token: Option<String>) -> Router {
+    let router = overlay::router(player)
+        .merge(health::router(player))
+        .route("/metrics", get(metrics));
+    match token {
+        Some(token) => router.merge(api_router(player, token)),
+        None => {
+            info!("CONTROL_API_TOKEN is not set, control API disabled.");
+            router
+        }
+    }
+}
+
+/// Serves `router` until the process exits.
+pub async fn serve(listener: TcpListener, router: Router) -> io::Result<()> {
+    axum::serve(listener, router).await
+}
+
+/// Runs the HTTP server on `BIND_ADDRESS`, with the control API if
+/// `CONTROL_API_TOKEN` is set.
+pub async fn serve_from_env() {
+    let token = env::var("CONTROL_API_TOKEN").ok();
+    let address = env::var("BIND_ADDRESS")
+        .unwrap_or_else(|_| "127.0.0.1:17564".to_string());
+    let result = async {
+        let address: SocketAddr = address.parse()?;
+        let listener = TcpListener::bind(address).await?;
+        info!(address = %address, "HTTP server listening.");
+        serve(listener, router(player(), token)).await?;
+        Ok::<(), Box<dyn Error>>(())
+    }
+    .await;
+    if let Err(e) = result {
+        error!("HTTP server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::tests::write_tone;
+    use crate::audio::NullBackend;
+    use crate::config::Settings;
+    use std::time::Duration;
+
+    /// Serves the API for a player with one sound on a random local port.
+    async fn start() -> (String, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        write_tone(&dir.path().join("Boom.wav"), Duration::from_millis(50));
+        let player = Player::new(
+            dir.path(),
+            Box::new(NullBackend::new()),
+            Settings::default(),
+        );
+        let player: &'static Player = Box::leak(Box::new(player));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = router(player, Some("letmein123".to_string()));
+        tokio::spawn(serve(listener, app));
+        (url, dir)
+    }
+
+    #[tokio::test]
+    async fn test_requires_bearer_token() {
+        let (url, _dir) = start().await;
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("{}/api/sounds", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+        let response = client
+            .get(format!("{}/api/sounds", url))
+            .bearer_auth("wrong")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics_without_token() {
+        let (url, _dir) = start().await;
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let text = response.text().await.unwrap();
+        assert!(text.contains("# TYPE soundbot_queue_depth gauge\n"));
+        assert!(text.contains("soundbot_playback_latency_seconds_count"));
+    }
+
+    #[tokio::test]
+    async fn test_serves_health_probes_without_token() {
+        let (url, _dir) = start().await;
+        let health = reqwest::get(format!("{}/healthz", url)).await.unwrap();
+        assert_eq!(health.status(), 200);
+        // No token or EventSub session in tests, so not ready.
+        let ready = reqwest::get(format!("{}/readyz", url)).await.unwrap();
+        assert_eq!(ready.status(), 503);
+        let checks: Value = ready.json().await.unwrap();
+        assert_eq!(checks["session_connected"], false);
+        assert_eq!(checks["audio_available"], true);
+    }
+
+    #[tokio::test]
+    async fn test_lists_plays_and_pauses() {
+        let (url, _dir) = start().await;
+        let client = reqwest::Client::new();
+        let call = |method: reqwest::Method, path: &str| {
+            client
+                .request(method, format!("{}{}", url, path))
+                .bearer_auth("letmein123")
+        };
+
+        let sounds: Vec<String> = call(reqwest::Method::GET, "/api/sounds")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(sounds, vec!["Boom"]);
+
+        let played = call(reqwest::Method::POST, "/api/sounds/boom/play")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(played.status(), 202);
+        let missing = call(reqwest::Method::POST, "/api/sounds/nope/play")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+
+        call(reqwest::Method::POST, "/api/pause")
+            .send()
+            .await
+            .unwrap();
+        let queue: Value = call(reqwest::Method::GET, "/api/queue")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(queue["paused"], true);
+    }
+}
@@ -0,0 +1,597 @@
+use rodio::cpal::traits::HostTrait;
+use rodio::{
+    cpal, Decoder, DeviceTrait, OutputStream, OutputStreamHandle, Sink, Source,
+};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Decoded audio held in memory as interleaved `f32` samples.
+#[derive(Clone)]
+pub struct Clip {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Arc<Vec<f32>>,
+}
+
+impl Clip {
+    /// Decodes a whole audio file into memory.
+    pub fn decode(path: &Path) -> Result<Clip, Box<dyn Error>> {
+        let file = File::open(path)?;
+        let decoder = Decoder::new(BufReader::new(file))?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let samples: Vec<f32> = decoder.convert_samples().collect();
+        Ok(Clip {
+            channels,
+            sample_rate,
+            samples: Arc::new(samples),
+        })
+    }
+
+    /// Playing time of the clip.
+    pub fn duration(&self) -> Duration {
+        let frames = self.samples.len() as f64 / self.channels.max(1) as f64;
+        Duration::from_secs_f64(frames / self.sample_rate.max(1) as f64)
+    }
+}
+
+/// Where a clip stops and how it fades, in frames (one sample per channel).
+#[derive(Clone, Copy, Debug)]
+struct Fade {
+    end: usize,
+    fade_in: usize,
+    fade_out: usize,
+}
+
+impl Fade {
+    fn new(clip: &Clip, options: &PlayOptions) -> Fade {
+        let rate = clip.sample_rate as f64;
+        let frames = |d: Duration| (d.as_secs_f64() * rate).round() as usize;
+        let total = clip.samples.len() / clip.channels.max(1) as usize;
+        let end = options
+            .max_duration
+            .map(|max| frames(max).min(total))
+            .unwrap_or(total);
+        Fade {
+            end,
+            fade_in: frames(options.fade_in).min(end),
+            fade_out: frames(options.fade_out).min(end),
+        }
+    }
+
+    /// Gain of the given frame, ramping linearly in and out.
+    fn gain(&self, frame: usize) -> f32 {
+        let mut gain = 1.0;
+        if frame < self.fade_in {
+            gain *= frame as f32 / self.fade_in as f32;
+        }
+        let left = self.end.saturating_sub(frame);
+        if left < self.fade_out {
+            gain *= left as f32 / self.fade_out as f32;
+        }
+        gain
+    }
+
+    /// Number of samples to play, across all channels.
+    fn samples(&self, clip: &Clip) -> usize {
+        self.end * clip.channels.max(1) as usize
+    }
+
+    fn duration(&self, clip: &Clip) -> Duration {
+        Duration::from_secs_f64(
+            self.end as f64 / clip.sample_rate.max(1) as f64,
+        )
+    }
+}
+
+/// Rodio source reading the shared samples of a clip without copying them,
+/// with fades and truncation applied on the fly.
+struct ClipSource {
+    clip: Clip,
+    fade: Fade,
+    pos: usize,
+}
+
+impl Iterator for ClipSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pos >= self.fade.samples(&self.clip) {
+            return None;
+        }
+        let frame = self.pos / self.clip.channels.max(1) as usize;
+        let sample = self.clip.samples.get(self.pos).copied();
+        self.pos += 1;
+        sample.map(|sample| sample * self.fade.gain(frame))
+    }
+}
+
+impl Source for ClipSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.fade.samples(&self.clip).saturating_sub(self.pos))
+    }
+
+    fn channels(&self) -> u16 {
+        self.clip.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.clip.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(self.fade.duration(&self.clip))
+    }
+}
+
+/// A clip that has been handed to a backend and may still be playing.
+pub trait Voice: Send + Sync {
+    /// Whether the clip has finished or was stopped.
+    fn is_finished(&self) -> bool;
+    /// Stops the clip right away.
+    fn stop(&self);
+    /// Current linear volume.
+    fn volume(&self) -> f32;
+    /// Changes the volume while the clip plays.
+    fn set_volume(&self, volume: f32);
+    /// Blocks until the clip has finished.
+    fn wait(&self) {
+        while !self.is_finished() {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// How a clip should be played.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayOptions<'a> {
+    /// Output device, or the backend's default device if `None`.
+    pub device: Option<&'a str>,
+    /// Linear volume, 1.0 plays the samples unchanged.
+    pub volume: f32,
+    /// Time over which the clip fades in from silence.
+    pub fade_in: Duration,
+    /// Time over which the clip fades out before it ends or is cut off.
+    pub fade_out: Duration,
+    /// Playing time after which the clip is cut off.
+    pub max_duration: Option<Duration>,
+}
+
+impl PlayOptions<'_> {
+    /// How long `clip` plays with these options.
+    pub fn played_duration(&self, clip: &Clip) -> Duration {
+        Fade::new(clip, self).duration(clip)
+    }
+}
+
+impl Default for PlayOptions<'_> {
+    fn default() -> Self {
+        PlayOptions {
+            device: None,
+            volume: 1.0,
+            fade_in: Duration::ZERO,
+            fade_out: Duration::ZERO,
+            max_duration: None,
+        }
+    }
+}
+
+/// Somewhere clips can be played: a sound card, a file, or nowhere at all.
+pub trait Backend: Send + Sync {
+    /// Starts playing `clip`; `name` identifies the sound for diagnostics.
+    fn play(
+        &self,
+        name: &str,
+        clip: &Clip,
+        options: PlayOptions,
+    ) -> Result<Box<dyn Voice>, Box<dyn Error>>;
+
+    /// Whether the output can currently be played to.
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// Names of the available output devices and the name of the default one.
+pub fn list_output_devices(
+) -> Result<(Vec<String>, Option<String>), Box<dyn Error>> {
+    let host = cpal::default_host();
+    let default = host.default_output_device().and_then(|d| d.name().ok());
+    let names = host
+        .output_devices()?
+        .filter_map(|device| device.name().ok())
+        .collect();
+    Ok((names, default))
+}
+
+/// Creates the backend selected by `AUDIO_BACKEND`: `rodio` (default, on
+/// `AUDIO_DEVICE` or the system default), `null`, or `wav` (written to
+/// `AUDIO_OUTPUT_DIR`, default `recordings`).
+pub fn backend_from_env() -> Box<dyn Backend> {
+    backend_with_device(env::var("AUDIO_DEVICE").ok())
+}
+
+/// Like [`backend_from_env`], but rodio plays on `device` instead of
+/// `AUDIO_DEVICE`.
+pub fn backend_with_device(device: Option<String>) -> Box<dyn Backend> {
+    match env::var("AUDIO_BACKEND").as_deref() {
+        Ok("null") => Box::new(NullBackend::new()),
+        Ok("wav") => {
+            let dir = env::var("AUDIO_OUTPUT_DIR")
+                .unwrap_or_else(|_| "recordings".to_string());
+            Box::new(WavBackend::new(PathBuf::from(dir)))
+        }
+        _ => Box::new(RodioBackend::new(device)),
+    }
+}
+
+/// Plays through the local output devices with rodio.
+pub struct RodioBackend {
+    default_device: Option<String>,
+    handles: Mutex<HashMap<Option<String>, OutputStreamHandle>>,
+}
+
+impl RodioBackend {
+    /// Plays on `default_device` unless a sound asks for another device;
+    /// `None` means the system default.
+    pub fn new(default_device: Option<String>) -> RodioBackend {
+        RodioBackend {
+            default_device,
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens the named device, falling back to the system default with a
+    /// warning if no output device has that name.
+    fn open_device(
+        name: Option<&str>,
+    ) -> Result<(OutputStream, OutputStreamHandle), Box<dyn Error>> {
+        if let Some(name) = name {
+            let found = cpal::default_host()
+                .output_devices()?
+                .find(|device| device.name().ok().as_deref() == Some(name));
+            match found {
+                Some(device) => {
+                    return Ok(OutputStream::try_from_device(&device)?)
+                }
+                None => warn!(
+                    device = name,
+                    "Audio device not found, using the default device."
+                ),
+            }
+        }
+        Ok(OutputStream::try_default()?)
+    }
+
+    /// Opens the output stream on a thread of its own. `OutputStream` cannot
+    /// be moved between threads, so that thread keeps it alive for the rest
+    /// of the process and only the (shareable) handle is returned.
+    fn open_stream(
+        name: Option<String>,
+    ) -> Result<OutputStreamHandle, Box<dyn Error>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            match RodioBackend::open_device(name.as_deref()) {
+                Ok((_stream, handle)) => {
+                    tx.send(Ok(handle)).ok();
+                    loop {
+                        std::thread::park();
+                    }
+                }
+                Err(e) => {
+                    tx.send(Err(e.to_string())).ok();
+                }
+            }
+        });
+        Ok(rx.recv()??)
+    }
+
+    fn stream_handle(
+        &self,
+        device: Option<&str>,
+    ) -> Result<OutputStreamHandle, Box<dyn Error>> {
+        let name = device
+            .map(str::to_string)
+            .or_else(|| self.default_device.clone());
+        let mut handles = self.handles.lock().unwrap();
+        if let Some(handle) = handles.get(&name) {
+            return Ok(handle.clone());
+        }
+        let handle = RodioBackend::open_stream(name.clone())?;
+        handles.insert(name, handle.clone());
+        Ok(handle)
+    }
+}
+
+struct RodioVoice(Sink);
+
+impl Voice for RodioVoice {
+    fn is_finished(&self) -> bool {
+        self.0.empty()
+    }
+
+    fn stop(&self) {
+        self.0.stop();
+    }
+
+    fn volume(&self) -> f32 {
+        self.0.volume()
+    }
+
+    fn set_volume(&self, volume: f32) {
+        self.0.set_volume(volume);
+    }
+}
+
+impl Backend for RodioBackend {
+    fn play(
+        &self,
+        _name: &str,
+        clip: &Clip,
+        options: PlayOptions,
+    ) -> Result<Box<dyn Voice>, Box<dyn Error>> {
+        let handle = self.stream_handle(options.device)?;
+        let sink = Sink::try_new(&handle)?;
+        sink.set_volume(options.volume);
+        sink.append(ClipSource {
+            clip: clip.clone(),
+            fade: Fade::new(clip, &options),
+            pos: 0,
+        });
+        Ok(Box::new(RodioVoice(sink)))
+    }
+
+    /// The configured device, or the system default it falls back to, must
+    /// be present.
+    fn is_available(&self) -> bool {
+        let host = cpal::default_host();
+        let named = self.default_device.as_deref().is_some_and(|name| {
+            host.output_devices().is_ok_and(|mut devices| {
+                devices
+                    .any(|device| device.name().ok().as_deref() == Some(name))
+            })
+        });
+        named || host.default_output_device().is_some()
+    }
+}
+
+/// A clip the null backend was asked to play.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayedClip {
+    pub name: String,
+    pub duration: Duration,
+    pub device: Option<String>,
+    pub volume: f32,
+}
+
+/// Voice of the WAV backend, which finishes as soon as it starts. Its volume
+/// is the one the file was written with.
+struct FinishedVoice(f32);
+
+impl Voice for FinishedVoice {
+    fn is_finished(&self) -> bool {
+        true
+    }
+
+    fn stop(&self) {}
+
+    fn volume(&self) -> f32 {
+        self.0
+    }
+
+    fn set_volume(&self, _volume: f32) {}
+}
+
+/// Voice of the null backend, which lasts as long as the clip would play.
+struct TimedVoice {
+    until: Instant,
+    stopped: AtomicBool,
+    /// Bits of the `f32` volume.
+    volume: AtomicU32,
+}
+
+impl Voice for TimedVoice {
+    fn is_finished(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst) || Instant::now() >= self.until
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::SeqCst))
+    }
+
+    fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.to_bits(), Ordering::SeqCst);
+    }
+}
+
+/// How many plays the null backend remembers.
+const MAX_RECORDED: usize = 1000;
+
+/// Plays nothing and records what would have played, taking as long as the
+/// clip would. Used by tests and on machines without audio hardware.
+#[derive(Clone)]
+pub struct NullBackend {
+    played: Arc<Mutex<Vec<PlayedClip>>>,
+}
+
+impl NullBackend {
+    pub fn new() -> NullBackend {
+        NullBackend {
+            played: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Clips played so far, in the order they were started.
+    #[cfg(test)]
+    pub fn played(&self) -> Vec<PlayedClip> {
+        self.played.lock().unwrap().clone()
+    }
+}
+
+impl Backend for NullBackend {
+    fn play(
+        &self,
+        name: &str,
+        clip: &Clip,
+        options: PlayOptions,
+    ) -> Result<Box<dyn Voice>, Box<dyn Error>> {
+        let duration = options.played_duration(clip);
+        info!(
+            sound = name,
+            device = options.device,
+            volume = options.volume,
+            duration_ms = duration.as_millis() as u64,
+            "Null backend: would have played sound."
+        );
+        let mut played = self.played.lock().unwrap();
+        if played.len() == MAX_RECORDED {
+            played.remove(0);
+        }
+        played.push(PlayedClip {
+            name: name.to_string(),
+            duration,
+            device: options.device.map(str::to_string),
+            volume: options.volume,
+        });
+        Ok(Box::new(TimedVoice {
+            until: Instant::now() + duration,
+            stopped: AtomicBool::new(false),
+            volume: AtomicU32::new(options.volume.to_bits()),
+        }))
+    }
+}
+
+/// Writes every clip, with its volume and fades applied, to a 32-bit float
+/// WAV file instead of playing it.
+pub struct WavBackend {
+    dir: PathBuf,
+    counter: AtomicU64,
+}
+
+impl WavBackend {
+    pub fn new(dir: PathBuf) -> WavBackend {
+        WavBackend {
+            dir,
+            counter: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Backend for WavBackend {
+    fn play(
+        &self,
+        name: &str,
+        clip: &Clip,
+        options: PlayOptions,
+    ) -> Result<Box<dyn Voice>, Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{}-{}-{}.wav", millis, n, name));
+
+        let spec = hound::WavSpec {
+            channels: clip.channels,
+            sample_rate: clip.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec)?;
+        let fade = Fade::new(clip, &options);
+        let channels = clip.channels.max(1) as usize;
+        for (i, sample) in clip.samples[..fade.samples(clip)].iter().enumerate()
+        {
+            let gain = fade.gain(i / channels) * options.volume;
+            writer.write_sample(*sample * gain)?;
+        }
+        writer.finalize()?;
+        info!(file = ?path, "Wrote clip to file.");
+        Ok(Box::new(FinishedVoice(options.volume)))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Writes a mono 16-bit WAV file with a 440 Hz tone.
+    pub fn write_tone(path: &Path, duration: Duration) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        let frames = (duration.as_secs_f64() * 8000.0) as u32;
+        for i in 0..frames {
+            let t = i as f32 / 8000.0;
+            let value = (t * 440.0 * std::f32::consts::TAU).sin() * 0.5;
+            writer
+                .write_sample((value * i16::MAX as f32) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_wav_backend_writes_decodable_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("tone.wav");
+        write_tone(&source, Duration::from_millis(250));
+
+        let clip = Clip::decode(&source).unwrap();
+        assert_eq!(clip.duration(), Duration::from_millis(250));
+
+        let out = dir.path().join("out");
+        let backend = WavBackend::new(out.clone());
+        let options = PlayOptions::default();
+        assert!(backend.play("tone", &clip, options).unwrap().is_finished());
+
+        let written = fs::read_dir(&out).unwrap().next().unwrap().unwrap();
+        let copy = Clip::decode(&written.path()).unwrap();
+        assert_eq!(copy.samples.len(), clip.samples.len());
+    }
+
+    #[test]
+    fn test_truncates_and_fades_clips() {
+        let clip = Clip {
+            channels: 2,
+            sample_rate: 100,
+            samples: Arc::new(vec![1.0; 2 * 100]),
+        };
+        let options = PlayOptions {
+            fade_in: Duration::from_millis(100),
+            fade_out: Duration::from_millis(200),
+            max_duration: Some(Duration::from_millis(500)),
+            ..PlayOptions::default()
+        };
+        assert_eq!(options.played_duration(&clip), Duration::from_millis(500));
+
+        let source = ClipSource {
+            clip: clip.clone(),
+            fade: Fade::new(&clip, &options),
+            pos: 0,
+        };
+        let samples: Vec<f32> = source.collect();
+        assert_eq!(samples.len(), 2 * 50);
+        // Silent at the start, full volume in the middle, and fading to
+        // silence where the clip is cut off.
+        assert_eq!(samples[0], 0.0);
+        assert_eq!(samples[2 * 20], 1.0);
+        assert!((samples[2 * 40] - 0.5).abs() < 1e-6);
+        assert!(samples[2 * 49] < 0.1);
+    }
+}
@@ -1,17 +1,114 @@
-use dirs;
+use crate::eventsub::{delete_subscription, list_subscriptions};
+use crate::helix::{self, Helix};
+use crate::logging;
+use crate::metrics;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{info, warn};
+use twitch_oauth2::tokens::errors::{
+    RefreshTokenError, RetrieveTokenError, ValidationError,
+};
 use twitch_oauth2::{
     tokens::UserTokenBuilder, AccessToken, ClientId, ClientSecret,
-    RefreshToken, Scope, TwitchToken, UserToken,
+    RefreshToken, RequestParseError, Scope, TwitchToken, UserToken,
 };
 use url::Url;
 
+/// When each access token in use expires, keyed by its file, for the
+/// readiness check.
+static TOKEN_EXPIRES_AT: Mutex<BTreeMap<PathBuf, Instant>> =
+    Mutex::new(BTreeMap::new());
+
+/// Whether tokens were obtained and none of them has expired yet.
+pub fn token_is_valid() -> bool {
+    let expires_at = TOKEN_EXPIRES_AT.lock().unwrap();
+    let now = Instant::now();
+    !expires_at.is_empty() && expires_at.values().all(|at| now < *at)
+}
+
+/// Whether checking a stored token failed because Twitch rejected the
+/// credentials, rather than because it could not be reached.
+fn is_rejected(error: &(dyn Error + 'static)) -> bool {
+    let Some(error) =
+        error.downcast_ref::<RetrieveTokenError<reqwest::Error>>()
+    else {
+        return false;
+    };
+    match error {
+        RetrieveTokenError::ValidationError(ValidationError::NotAuthorized) => {
+            true
+        }
+        RetrieveTokenError::RefreshTokenError(
+            RefreshTokenError::RequestParseError(
+                RequestParseError::TwitchError(response),
+            ),
+        ) => {
+            response.status == StatusCode::BAD_REQUEST
+                || response.status == StatusCode::UNAUTHORIZED
+        }
+        _ => false,
+    }
+}
+
+/// Keeps the access and refresh token out of every log line.
+fn register_token_secrets(token: &UserToken) {
+    logging::register_secret(token.token().secret());
+    if let Some(refresh) = &token.refresh_token {
+        logging::register_secret(refresh.secret());
+    }
+}
+
+/// Stores a token that was refreshed while running, so the next start uses
+/// it, and keeps the readiness check current.
+pub fn store_refreshed_token(path: &PathBuf, token: &UserToken) {
+    metrics::TOKEN_REFRESHES.inc();
+    register_token_secrets(token);
+    TOKEN_EXPIRES_AT
+        .lock()
+        .unwrap()
+        .insert(path.clone(), Instant::now() + token.expires_in());
+    let Some(refresh_token) = &token.refresh_token else {
+        return;
+    };
+    let stored = StoredToken {
+        access_token: token.token().secret().to_string(),
+        refresh_token: refresh_token.secret().to_string(),
+    };
+    if let Err(e) = stored.write(path) {
+        warn!(path = ?path, "Failed to store the refreshed token: {}", e);
+    }
+}
+
+/// Deletes every EventSub subscription of the client id, logging the ones
+/// that could not be deleted.
+async fn delete_all_subscriptions(helix: &Helix) {
+    let subscriptions = match list_subscriptions(helix).await {
+        Ok(list) => list.subscriptions,
+        Err(e) => {
+            warn!("Failed to list subscriptions: {}", e);
+            return;
+        }
+    };
+    for sub in subscriptions {
+        match delete_subscription(helix, &sub.id).await {
+            Ok(()) => info!(subscription_id = %sub.id, "Deleted subscription."),
+            Err(e) => warn!(
+                subscription_id = %sub.id,
+                "Failed to delete subscription: {}",
+                e
+            ),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct StoredToken {
     access_token: String,
@@ -30,15 +127,26 @@ impl StoredToken {
         Ok(stored)
     }
 
-    fn token_path() -> Result<PathBuf, Box<dyn Error>> {
+    /// Path of the token of a channel from the `channels` setting, or of
+    /// `token.json` for the `BROADCASTER_ID` channel.
+    pub fn token_path(
+        channel: Option<&str>,
+    ) -> Result<PathBuf, Box<dyn Error>> {
         let mut path =
             dirs::config_dir().ok_or("Could not find config directory.")?;
         path.push("twitch-soundbot");
-        path.push("token.json");
+        match channel {
+            Some(login) => {
+                path.push(format!("token-{}.json", login.to_lowercase()))
+            }
+            None => path.push("token.json"),
+        }
         Ok(path)
     }
 
-    async fn create_twitch_token() -> Result<UserToken, Box<dyn Error>> {
+    pub async fn create_twitch_token(
+        channel: Option<&str>,
+    ) -> Result<UserToken, Box<dyn Error>> {
         let client_id = ClientId::new(env::var("CLIENT_ID").unwrap());
         let client_secret =
             ClientSecret::new(env::var("CLIENT_SECRET").unwrap());
@@ -46,13 +154,21 @@ impl StoredToken {
 
         println!("This is the redirect url we have generated: {redirect}\n\n");
 
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()?;
+        let client = helix::http();
 
         let mut builder =
             UserTokenBuilder::new(client_id, client_secret, redirect)
-                .set_scopes(vec![Scope::ChannelReadRedemptions])
+                // Managing redemptions includes reading them, and allows
+                // refunds. The read scopes let rewards be limited to roles,
+                // and the chat scopes let the bot read commands and reply.
+                .set_scopes(vec![
+                    Scope::ChannelManageRedemptions,
+                    Scope::ChannelReadSubscriptions,
+                    Scope::ChannelReadVips,
+                    Scope::ModerationRead,
+                    Scope::UserReadChat,
+                    Scope::UserWriteChat,
+                ])
                 .force_verify(true);
 
         let (url, _csrf) = builder.generate_url();
@@ -69,7 +185,7 @@ impl StoredToken {
         let code = params.get("code").ok_or("Missing code")?;
         let state = params.get("state").ok_or("Missing state")?;
 
-        let user_token = builder.get_user_token(&client, state, code).await?;
+        let user_token = builder.get_user_token(client, state, code).await?;
 
         let token = StoredToken {
             access_token: user_token.token().secret().to_string(),
@@ -81,7 +197,7 @@ impl StoredToken {
                 .to_string(),
         };
 
-        token.write(&StoredToken::token_path()?)?;
+        token.write(&StoredToken::token_path(channel)?)?;
 
         Ok(user_token)
     }
@@ -91,31 +207,127 @@ impl StoredToken {
         let client_secret =
             ClientSecret::new(env::var("CLIENT_SECRET").unwrap());
 
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()?;
+        let client = helix::http();
 
-        let access = AccessToken::new(self.access_token);
+        let access = AccessToken::new(self.access_token.clone());
         let refresh = RefreshToken::new(self.refresh_token);
 
         let user_token = UserToken::from_existing_or_refresh_token(
-            &client,
+            client,
             access,
             refresh,
             client_id,
             Some(client_secret),
         )
         .await?;
+        if user_token.token().secret() != self.access_token {
+            metrics::TOKEN_REFRESHES.inc();
+            info!("Refreshed the access token.");
+        }
 
         Ok(user_token)
     }
 
-    pub async fn ensure_twitch_token() -> Result<UserToken, Box<dyn Error>> {
-        let token: UserToken =
-            match StoredToken::read(&StoredToken::token_path()?) {
-                Ok(token) => token.check_twitch_token().await.unwrap(),
-                Err(_) => StoredToken::create_twitch_token().await.unwrap(),
-            };
+    /// Reads the stored token of a channel, refreshing it if needed, or
+    /// logs in if there is none. `None` is the `BROADCASTER_ID` channel.
+    pub async fn ensure_twitch_token(
+        channel: Option<&str>,
+    ) -> Result<UserToken, Box<dyn Error>> {
+        let path = StoredToken::token_path(channel)?;
+        let token: UserToken = match StoredToken::read(&path) {
+            Ok(token) => token.check_twitch_token().await.unwrap(),
+            Err(_) => StoredToken::create_twitch_token(channel).await.unwrap(),
+        };
+        register_token_secrets(&token);
+        TOKEN_EXPIRES_AT
+            .lock()
+            .unwrap()
+            .insert(path, Instant::now() + token.expires_in());
         Ok(token)
     }
+
+    /// Logs out: optionally deletes every EventSub subscription created by
+    /// this client id, revokes the stored access token on Twitch's side and
+    /// removes `token.json`, or the token file of `channel`. If Twitch rejects
+    /// the stored credentials the file is still removed, since there is
+    /// nothing to revoke; any other failure keeps it for another try.
+    pub async fn logout(
+        channel: Option<&str>,
+        delete_subscriptions: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = StoredToken::token_path(channel)?;
+        let stored = match StoredToken::read(&path) {
+            Ok(stored) => stored,
+            Err(_) => {
+                info!("No stored token found.");
+                return Ok(());
+            }
+        };
+
+        match stored.check_twitch_token().await {
+            Ok(user_token) => {
+                // Failing to delete subscriptions must not keep the token
+                // alive, so the revocation below always runs.
+                if delete_subscriptions {
+                    delete_all_subscriptions(&Helix::new(
+                        user_token.clone(),
+                        None,
+                    ))
+                    .await;
+                }
+
+                user_token.revoke_token(helix::http()).await?;
+                info!("Revoked access token on Twitch.");
+            }
+            Err(e) if is_rejected(e.as_ref()) => {
+                warn!(
+                    "Stored token is no longer valid ({}), skipping \
+                     revocation.",
+                    e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+
+        fs::remove_file(&path)?;
+        info!(path = ?path, "Removed stored token.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twitch_oauth2::id::TwitchTokenErrorResponse;
+
+    fn refresh_failed(status: StatusCode) -> Box<dyn Error> {
+        let response = TwitchTokenErrorResponse {
+            status,
+            message: "Invalid refresh token".to_string(),
+            error: None,
+        };
+        RetrieveTokenError::<reqwest::Error>::RefreshTokenError(
+            RefreshTokenError::RequestParseError(response.into()),
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_only_rejected_tokens_count_as_invalid() {
+        let expired: Box<dyn Error> =
+            RetrieveTokenError::<reqwest::Error>::ValidationError(
+                ValidationError::NotAuthorized,
+            )
+            .into();
+        assert!(is_rejected(expired.as_ref()));
+        assert!(is_rejected(
+            refresh_failed(StatusCode::BAD_REQUEST).as_ref()
+        ));
+
+        assert!(!is_rejected(
+            refresh_failed(StatusCode::SERVICE_UNAVAILABLE).as_ref()
+        ));
+        let unrelated: Box<dyn Error> = "connection refused".into();
+        assert!(!is_rejected(unrelated.as_ref()));
+    }
 }
@@ -0,0 +1,118 @@
+use crate::audio::Clip;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Decoded clips kept in memory within a byte budget. When a new clip does
+/// not fit, the least recently used clips are evicted first.
+pub struct ClipCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<PathBuf, (Clip, u64)>,
+}
+
+/// Memory taken by the samples of a clip.
+fn clip_size(clip: &Clip) -> usize {
+    clip.samples.len() * std::mem::size_of::<f32>()
+}
+
+impl ClipCache {
+    /// Creates a cache holding at most `budget` bytes of samples. A budget
+    /// of zero disables caching.
+    pub fn new(budget: usize) -> ClipCache {
+        ClipCache {
+            budget,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached clip for `path` and marks it as recently used.
+    pub fn get(&mut self, path: &Path) -> Option<Clip> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(path).map(|(clip, last_used)| {
+            *last_used = tick;
+            clip.clone()
+        })
+    }
+
+    /// Whether a clip of `size` bytes fits without evicting anything.
+    pub fn has_room_for(&self, size: usize) -> bool {
+        self.used + size <= self.budget
+    }
+
+    /// Stores a clip, evicting least recently used clips to make room.
+    /// Returns `false` if the clip is larger than the whole budget.
+    pub fn insert(&mut self, path: PathBuf, clip: Clip) -> bool {
+        let size = clip_size(&clip);
+        if size > self.budget {
+            return false;
+        }
+        if let Some((old, _)) = self.entries.remove(&path) {
+            self.used -= clip_size(&old);
+        }
+        while !self.has_room_for(size) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(oldest) => {
+                    let (evicted, _) = self.entries.remove(&oldest).unwrap();
+                    self.used -= clip_size(&evicted);
+                }
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.used += size;
+        self.entries.insert(path, (clip, self.tick));
+        true
+    }
+
+    /// Bytes currently used by cached samples.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn clip(samples: usize) -> Clip {
+        Clip {
+            channels: 1,
+            sample_rate: 8000,
+            samples: Arc::new(vec![0.0; samples]),
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // Room for two clips of 10 samples (40 bytes each).
+        let mut cache = ClipCache::new(80);
+        assert!(cache.insert(PathBuf::from("a"), clip(10)));
+        assert!(cache.insert(PathBuf::from("b"), clip(10)));
+
+        // Touch "a" so "b" becomes the least recently used.
+        assert!(cache.get(Path::new("a")).is_some());
+        assert!(cache.insert(PathBuf::from("c"), clip(10)));
+
+        assert!(cache.get(Path::new("a")).is_some());
+        assert!(cache.get(Path::new("b")).is_none());
+        assert!(cache.get(Path::new("c")).is_some());
+        assert_eq!(cache.used(), 80);
+    }
+
+    #[test]
+    fn test_rejects_clips_larger_than_budget() {
+        let mut cache = ClipCache::new(16);
+        assert!(!cache.insert(PathBuf::from("big"), clip(10)));
+        assert_eq!(cache.used(), 0);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::audio::backend_with_device;
+use crate::chat::{self, ChatSettings};
+use crate::config::{
+    config_path, data_path, load_settings, load_settings_from, set_config_value,
+};
+use crate::eventsub::ChannelSubscriber;
+use crate::helix::{self, Helix};
+use crate::redemption::{self, Moderation};
+use crate::sound::{self, Player};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// A channel from the `channels` list in `settings.json`.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ChannelSettings {
+    /// Login of the broadcaster.
+    pub login: String,
+    /// Sounds of the channel. Defaults to `SOUNDS_DIR`.
+    pub sounds_dir: Option<String>,
+    /// Output device of the channel. Defaults to `AUDIO_DEVICE`.
+    pub audio_device: Option<String>,
+    /// Settings file of the channel, in the format of `settings.json` and
+    /// next to it unless absolute, for its sounds, moderation and chat.
+    /// Without one the channel uses the defaults.
+    pub settings: Option<String>,
+}
+
+/// A channel the bot plays alerts for, with everything that is set up per
+/// channel.
+pub struct Channel {
+    pub login: String,
+    pub player: &'static Player,
+    pub moderation: &'static Moderation,
+    pub chat: &'static ChatSettings,
+}
+
+impl Channel {
+    /// Builds a channel from its entry in `channels`. The channel lives for
+    /// the rest of the process.
+    pub fn build(
+        channel: &ChannelSettings,
+    ) -> Result<&'static Channel, Box<dyn Error>> {
+        let settings = match &channel.settings {
+            Some(file) => load_settings_from(&data_path(file)?),
+            None => Default::default(),
+        };
+        let dir = channel
+            .sounds_dir
+            .clone()
+            .or_else(|| env::var("SOUNDS_DIR").ok())
+            .unwrap_or_else(|| "sounds".into());
+        let device = channel
+            .audio_device
+            .clone()
+            .or_else(|| env::var("AUDIO_DEVICE").ok());
+        let player = Player::from_env_with(
+            &PathBuf::from(dir),
+            backend_with_device(device),
+            settings.clone(),
+        );
+        let chat = settings.chat.clone();
+        Ok(Box::leak(Box::new(Channel {
+            login: channel.login.to_lowercase(),
+            player: Box::leak(Box::new(player)),
+            moderation: Box::leak(Box::new(Moderation::new(settings))),
+            chat: Box::leak(Box::new(chat)),
+        })))
+    }
+}
+
+/// The `BROADCASTER_ID` channel, set up by `.env` and `settings.json`. It
+/// also owns the control API and the overlay.
+static PRIMARY: Lazy<Channel> = Lazy::new(|| Channel {
+    login: env::var("BROADCASTER_ID").unwrap_or_default(),
+    player: sound::player(),
+    moderation: redemption::moderation(),
+    chat: chat::settings(),
+});
+
+/// Channels by numeric broadcaster id, once resolved.
+static CHANNELS: Lazy<Mutex<HashMap<String, &'static Channel>>> =
+    Lazy::new(Default::default);
+
+/// Makes events of `broadcaster_id` go to `channel`.
+pub fn register(broadcaster_id: &str, channel: &'static Channel) {
+    CHANNELS
+        .lock()
+        .unwrap()
+        .insert(broadcaster_id.to_string(), channel);
+}
+
+/// The channel events of `broadcaster_id` belong to; the `BROADCASTER_ID`
+/// channel if it is not a known one.
+pub fn for_broadcaster(broadcaster_id: &str) -> &'static Channel {
+    CHANNELS
+        .lock()
+        .unwrap()
+        .get(broadcaster_id)
+        .copied()
+        .unwrap_or(&PRIMARY)
+}
+
+/// Every channel: the `BROADCASTER_ID` one and the registered ones.
+pub fn all() -> Vec<&'static Channel> {
+    let mut channels: Vec<&'static Channel> = vec![&PRIMARY];
+    for channel in CHANNELS.lock().unwrap().values() {
+        if !channels.iter().any(|known| std::ptr::eq(*known, *channel)) {
+            channels.push(channel);
+        }
+    }
+    channels
+}
+
+/// Stops the clips playing on every channel, so playback that outlived the
+/// drain does not keep the process alive.
+pub fn stop_all() {
+    for channel in all() {
+        channel.player.stop_all();
+    }
+}
+
+/// Saves the state every channel keeps on disk, before exiting.
+pub fn flush() {
+    for channel in all() {
+        channel.player.flush();
+    }
+}
+
+/// The numeric id of `broadcaster` (a login or a numeric id) if it is the
+/// user the token belongs to, as Twitch requires for subscribing to
+/// redemptions and managing rewards.
+fn owner_id(
+    broadcaster: &str,
+    token_login: &str,
+    token_user_id: &str,
+) -> Result<String, Box<dyn Error>> {
+    if broadcaster.eq_ignore_ascii_case(token_login)
+        || broadcaster == token_user_id
+    {
+        return Ok(token_user_id.to_string());
+    }
+    Err(format!(
+        "The stored token belongs to {}, not to {}; log in again as {}.",
+        token_login, broadcaster, broadcaster
+    )
+    .into())
+}
+
+/// Resolves `broadcaster` (a login or a numeric id) to its numeric id from
+/// the validated token of `helix`, failing if the token belongs to someone
+/// else.
+pub fn broadcaster_id(
+    broadcaster: &str,
+    helix: &Helix,
+) -> Result<String, Box<dyn Error>> {
+    owner_id(broadcaster, helix.login(), helix.user_id())
+}
+
+/// Config key the numeric id of the `BROADCASTER_ID` channel is cached
+/// under.
+const NUMERIC_ID_KEY: &str = "BROADCASTER_NUMERIC_ID";
+
+/// Resolves the `BROADCASTER_ID` channel like [`broadcaster_id`] and caches
+/// its numeric id in the config file. The cached id is read first and only
+/// rewritten when the token now resolves to another one.
+pub fn primary_broadcaster_id(helix: &Helix) -> Result<String, Box<dyn Error>> {
+    let cached = env::var(NUMERIC_ID_KEY).ok();
+    let id = broadcaster_id(&PRIMARY.login, helix)?;
+    if cached.as_deref() == Some(id.as_str()) {
+        return Ok(id);
+    }
+    match set_config_value(&config_path()?, NUMERIC_ID_KEY, &id) {
+        Ok(()) => info!(broadcaster_id = %id, "Cached the broadcaster id."),
+        Err(e) => warn!("Failed to cache the broadcaster id: {}", e),
+    }
+    Ok(id)
+}
+
+/// Sets up the `BROADCASTER_ID` channel with `helix` and every channel in
+/// `channels` with its own token, logging in where there is none yet.
+/// Returns what each channel subscribes to.
+pub async fn load(
+    helix: Helix,
+) -> Result<Vec<ChannelSubscriber>, Box<dyn Error>> {
+    let primary_id = primary_broadcaster_id(&helix)?;
+    info!(broadcaster_id = %primary_id, "Resolved broadcaster.");
+    register(&primary_id, &PRIMARY);
+    let mut subscribers = vec![ChannelSubscriber {
+        broadcaster_id: primary_id,
+        helix,
+        chat: PRIMARY.chat.enabled,
+    }];
+
+    for settings in load_settings().channels {
+        let helix = helix::connect(Some(&settings.login)).await?;
+        let id = broadcaster_id(&settings.login, &helix)?;
+        let channel = Channel::build(&settings)?;
+        // Decode the sounds up front so the first redemption is instant.
+        channel.player.prewarm();
+        register(&id, channel);
+        info!(channel = %channel.login, broadcaster_id = %id, "Added channel.");
+        subscribers.push(ChannelSubscriber {
+            broadcaster_id: id,
+            helix,
+            chat: channel.chat.enabled,
+        });
+    }
+    Ok(subscribers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullBackend;
+    use crate::config::Settings;
+    use std::path::Path;
+
+    #[test]
+    fn test_routes_events_by_broadcaster() {
+        let settings: Settings = serde_json::from_str(
+            r#"{ "channels": [{ "login": "Other", "audio_device": "Desk" }] }"#,
+        )
+        .unwrap();
+        assert_eq!(settings.channels[0].login, "Other");
+        assert_eq!(settings.channels[0].audio_device.as_deref(), Some("Desk"));
+        assert!(settings.channels[0].settings.is_none());
+
+        let channel: &'static Channel = Box::leak(Box::new(Channel {
+            login: "other".to_string(),
+            player: Box::leak(Box::new(Player::new(
+                Path::new("/nonexistent"),
+                Box::new(NullBackend::new()),
+                Settings::default(),
+            ))),
+            moderation: Box::leak(Box::new(Moderation::new(
+                Settings::default(),
+            ))),
+            chat: Box::leak(Box::new(ChatSettings::default())),
+        }));
+        register("test-9001", channel);
+        assert!(std::ptr::eq(for_broadcaster("test-9001"), channel));
+        assert!(std::ptr::eq(for_broadcaster("test-unknown"), &*PRIMARY));
+    }
+
+    #[test]
+    fn test_resolves_the_token_owner_by_login() {
+        assert_eq!(owner_id("ME", "me", "111").unwrap(), "111");
+    }
+
+    #[test]
+    fn test_resolves_the_token_owner_by_numeric_id() {
+        assert_eq!(owner_id("111", "me", "111").unwrap(), "111");
+    }
+
+    #[test]
+    fn test_rejects_broadcasters_other_than_the_token_owner() {
+        let error = owner_id("other", "me", "111").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "The stored token belongs to me, not to other; log in again as \
+             other."
+        );
+        assert!(owner_id("222", "me", "111").is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::channels::{self, Channel};
+use crate::config::load_settings;
+use crate::eventsub::send_chat_message;
+use crate::helix::Helix;
+use crate::redemption::{field, Role};
+use crate::sound::{EventKind, Outcome};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+/// Longest chat message Twitch accepts, in characters.
+const MAX_MESSAGE_CHARS: usize = 500;
+
+/// Chat options in `settings.json`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChatSettings {
+    /// Whether the bot reads chat commands and replies in chat.
+    pub enabled: bool,
+    /// What chat commands start with.
+    pub prefix: String,
+    /// Replies by outcome.
+    pub messages: ChatMessages,
+}
+
+impl Default for ChatSettings {
+    fn default() -> ChatSettings {
+        ChatSettings {
+            enabled: false,
+            prefix: "!".to_string(),
+            messages: ChatMessages::default(),
+        }
+    }
+}
+
+/// The reply to each outcome of a redemption. `{user}`, `{reward}` and
+/// `{input}` are filled in; an empty message sends nothing. Outcomes left
+/// out of `settings.json` keep their default reply.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChatMessages {
+    pub handled: String,
+    pub no_match: String,
+    pub paused: String,
+    pub blocked: String,
+    pub skipped: String,
+    pub failed: String,
+}
+
+impl Default for ChatMessages {
+    fn default() -> ChatMessages {
+        ChatMessages {
+            handled: String::new(),
+            no_match: "@{user} there is no sound called \"{input}\"."
+                .to_string(),
+            paused: "@{user} sounds are paused right now.".to_string(),
+            blocked: "@{user} you can't play {reward}.".to_string(),
+            skipped: String::new(),
+            failed: String::new(),
+        }
+    }
+}
+
+impl ChatSettings {
+    /// The reply to an outcome, if one is configured.
+    pub fn reply(
+        &self,
+        outcome: Outcome,
+        user: &str,
+        reward: &str,
+        input: &str,
+    ) -> Option<String> {
+        let messages = &self.messages;
+        let template = match outcome {
+            Outcome::Handled { .. } => &messages.handled,
+            Outcome::NoMatch => &messages.no_match,
+            Outcome::Paused => &messages.paused,
+            Outcome::Blocked => &messages.blocked,
+            Outcome::Skipped => &messages.skipped,
+            Outcome::Failed => &messages.failed,
+        };
+        if template.is_empty() {
+            return None;
+        }
+        Some(
+            template
+                .replace("{user}", user)
+                .replace("{reward}", reward)
+                .replace("{input}", input),
+        )
+    }
+}
+
+/// Chat options, read from `settings.json` once.
+static CHAT: Lazy<ChatSettings> = Lazy::new(|| load_settings().chat);
+
+/// Chat options of the `BROADCASTER_ID` channel.
+pub fn settings() -> &'static ChatSettings {
+    &CHAT
+}
+
+/// A command typed in chat.
+#[derive(Debug, PartialEq)]
+pub enum ChatCommand {
+    /// Plays the sound closest to the given name.
+    Sound(String),
+    /// Stops the most recently started sound.
+    Skip,
+    /// Lists the sounds.
+    Sounds,
+    /// Pauses alerts.
+    Pause,
+    /// Resumes alerts.
+    Resume,
+}
+
+impl ChatCommand {
+    /// Parses a chat message, if it is a command.
+    pub fn parse(prefix: &str, text: &str) -> Option<ChatCommand> {
+        let rest = text.trim().strip_prefix(prefix)?;
+        let (name, arg) = rest
+            .split_once(char::is_whitespace)
+            .map_or((rest, ""), |(name, arg)| (name, arg.trim()));
+        match name.to_lowercase().as_str() {
+            "sound" if !arg.is_empty() => {
+                Some(ChatCommand::Sound(arg.to_string()))
+            }
+            "skip" => Some(ChatCommand::Skip),
+            "sounds" => Some(ChatCommand::Sounds),
+            "pause" => Some(ChatCommand::Pause),
+            "resume" => Some(ChatCommand::Resume),
+            _ => None,
+        }
+    }
+
+    /// Whether only moderators and the broadcaster may use the command.
+    fn needs_moderator(&self) -> bool {
+        !matches!(self, ChatCommand::Sounds)
+    }
+}
+
+/// Roles shown by the badges of a chat message.
+pub fn badge_roles(event: &Value) -> Vec<Role> {
+    let Some(badges) = event.get("badges").and_then(|b| b.as_array()) else {
+        return Vec::new();
+    };
+    badges
+        .iter()
+        .filter_map(|badge| match badge.get("set_id")?.as_str()? {
+            "broadcaster" => Some(Role::Broadcaster),
+            "moderator" => Some(Role::Moderator),
+            "vip" => Some(Role::Vip),
+            "subscriber" | "founder" => Some(Role::Subscriber),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Shortens a message to what Twitch accepts.
+fn truncate(message: String) -> String {
+    if message.chars().count() <= MAX_MESSAGE_CHARS {
+        return message;
+    }
+    let mut short: String =
+        message.chars().take(MAX_MESSAGE_CHARS - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Posts a message in the broadcaster's chat as the bot's user, warning if
+/// that fails.
+async fn send(
+    helix: &Helix,
+    broadcaster_id: &str,
+    message: String,
+    reply_to: Option<&str>,
+) {
+    if let Err(e) =
+        send_chat_message(helix, broadcaster_id, &truncate(message), reply_to)
+            .await
+    {
+        warn!("{}", e);
+    }
+}
+
+/// Tells chat how a redemption went, with the message configured for its
+/// outcome, if chat is enabled for the channel.
+pub async fn report(event: &Value, outcome: Outcome, helix: &Helix) {
+    let broadcaster_id =
+        field(event, &["broadcaster_user_id"]).unwrap_or_default();
+    let chat = channels::for_broadcaster(broadcaster_id).chat;
+    if !chat.enabled {
+        return;
+    }
+    let Some(message) = chat.reply(
+        outcome,
+        field(event, &["user_name"]).unwrap_or_default(),
+        field(event, &["reward", "title"]).unwrap_or_default(),
+        field(event, &["user_input"]).unwrap_or_default(),
+    ) else {
+        return;
+    };
+    send(helix, broadcaster_id, message, None).await;
+}
+
+/// Plays a sound for `!sound`, with the same moderation, allow lists and
+/// pause handling as a redemption. Returns the reply, if any.
+async fn play_command(
+    channel: &'static Channel,
+    event: &Value,
+    name: &str,
+    roles: &[Role],
+) -> Option<String> {
+    let chat = channel.chat;
+    let user_id = field(event, &["chatter_user_id"]).unwrap_or_default();
+    let login = field(event, &["chatter_user_login"]).unwrap_or_default();
+    let user = field(event, &["chatter_user_name"])
+        .unwrap_or_default()
+        .to_string();
+    if let Some(reason) = channel.moderation.screen(user_id, login, name) {
+        info!(reason, "Blocked chat command.");
+        return chat.reply(Outcome::Blocked, &user, name, name);
+    }
+    let Some(sound) = channel.player.resolve_input(name) else {
+        return chat.reply(Outcome::NoMatch, &user, name, name);
+    };
+    if let Some(required) =
+        channel.moderation.required_roles(sound, user_id, login)
+    {
+        if !required.iter().any(|role| roles.contains(role)) {
+            info!(sound, "Chat command blocked by the sound's allow list.");
+            return chat.reply(Outcome::Blocked, &user, sound, name);
+        }
+    }
+    let sound = sound.to_string();
+    let outcome = tokio::task::spawn_blocking({
+        let (user, sound) = (user.clone(), sound.clone());
+        move || channel.player.play_sound(&user, &sound, EventKind::Chat)
+    })
+    .await
+    .unwrap_or(Outcome::Failed);
+    chat.reply(outcome, &user, &sound, name)
+}
+
+/// Handles a chat message: runs it if it is a chat command the chatter may
+/// use, and replies in chat.
+pub async fn handle_message(payload: Value, helix: Helix) {
+    let event = payload.get("event").cloned().unwrap_or_default();
+    let broadcaster_id =
+        field(&event, &["broadcaster_user_id"]).unwrap_or_default();
+    let channel = channels::for_broadcaster(broadcaster_id);
+    let text = field(&event, &["message", "text"]).unwrap_or_default();
+    let Some(command) = ChatCommand::parse(&channel.chat.prefix, text) else {
+        return;
+    };
+    let roles = badge_roles(&event);
+    if command.needs_moderator()
+        && !roles.contains(&Role::Moderator)
+        && !roles.contains(&Role::Broadcaster)
+    {
+        return;
+    }
+    info!(
+        user = field(&event, &["chatter_user_login"]).unwrap_or_default(),
+        command = ?command,
+        "Chat command."
+    );
+
+    let reply = match command {
+        ChatCommand::Sound(name) => {
+            play_command(channel, &event, &name, &roles).await
+        }
+        ChatCommand::Skip => {
+            let skipped =
+                tokio::task::spawn_blocking(|| channel.player.skip_current())
+                    .await
+                    .ok()
+                    .flatten();
+            Some(match skipped {
+                Some(sound) => format!("Skipped {}.", sound),
+                None => "Nothing is playing.".to_string(),
+            })
+        }
+        ChatCommand::Sounds => {
+            let names = channel.player.sound_names();
+            Some(format!("Sounds: {}", names.join(", ")))
+        }
+        ChatCommand::Pause => {
+            channel.player.set_paused(true);
+            Some("Sounds are paused.".to_string())
+        }
+        ChatCommand::Resume => {
+            channel.player.set_paused(false);
+            Some("Sounds are back on.".to_string())
+        }
+    };
+    if let Some(reply) = reply {
+        let message_id = field(&event, &["message_id"]);
+        send(&helix, broadcaster_id, reply, message_id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parses_chat_commands() {
+        let parse = |text| ChatCommand::parse("!", text);
+        assert_eq!(
+            parse("!sound  air horn "),
+            Some(ChatCommand::Sound("air horn".to_string()))
+        );
+        assert_eq!(parse("!SKIP"), Some(ChatCommand::Skip));
+        assert_eq!(parse("!sounds"), Some(ChatCommand::Sounds));
+        assert_eq!(parse("!sound"), None);
+        assert_eq!(parse("hello !skip"), None);
+        assert!(!ChatCommand::Sounds.needs_moderator());
+        assert!(ChatCommand::Pause.needs_moderator());
+    }
+
+    #[test]
+    fn test_reads_roles_from_badges() {
+        let event = json!({
+            "badges": [
+                { "set_id": "moderator", "id": "1" },
+                { "set_id": "founder", "id": "0" },
+                { "set_id": "glhf-pledge", "id": "1" }
+            ]
+        });
+        assert_eq!(
+            badge_roles(&event),
+            vec![Role::Moderator, Role::Subscriber]
+        );
+        assert!(badge_roles(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_renders_configured_replies() {
+        let settings: ChatSettings = serde_json::from_str(
+            r#"{
+                "enabled": true,
+                "messages": { "no_match": "@{user} no {input}", "blocked": "" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.reply(Outcome::NoMatch, "Viewer", "Any", "fo"),
+            Some("@Viewer no fo".to_string())
+        );
+        assert_eq!(settings.reply(Outcome::Blocked, "Viewer", "Any", ""), None);
+        let handled = Outcome::Handled { clip: None };
+        assert_eq!(settings.reply(handled, "Viewer", "Any", ""), None);
+        assert_eq!(settings.prefix, "!");
+
+        // Outcomes left out keep their default reply.
+        assert!(settings
+            .reply(Outcome::Paused, "Viewer", "Any", "")
+            .is_some_and(|m| m.contains("paused")));
+
+        let defaults = ChatSettings::default();
+        assert!(defaults
+            .reply(Outcome::Paused, "Viewer", "Horn", "")
+            .is_some_and(|m| m.starts_with("@Viewer")));
+        assert_eq!(truncate("x".repeat(600)).chars().count(), 500);
+    }
+}
@@ -0,0 +1,413 @@
+use crate::audio::list_output_devices;
+use crate::auth::StoredToken;
+use crate::channels;
+use crate::config::{
+    check_config, config_path, ensure_config, interactive_setup,
+};
+use crate::eventsub::{
+    delete_subscription, get_custom_rewards, list_subscriptions,
+    run_eventsub_ws_service,
+};
+use crate::helix;
+use crate::history::{self, open_from_env};
+use crate::logging::LogFormat;
+use crate::recording;
+use crate::sound::{
+    listen_for_control_signals, play_test_sound, prewarm, sound_names,
+};
+use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::path::PathBuf;
+
+/// Command-line interface of the soundbot.
+#[derive(Parser)]
+#[command(name = "twitch-soundbot", version, about)]
+pub struct Cli {
+    /// Log level: error, warn, info, debug or trace (or `LOG_LEVEL`).
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+    /// Log output format (or `LOG_FORMAT`).
+    #[arg(long, global = true, value_enum)]
+    pub log_format: Option<LogFormat>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Connect to EventSub and play sounds for redemptions (default).
+    Run {
+        /// Also record every received EventSub message to a timestamped
+        /// JSONL file next to the config, for `replay`.
+        #[arg(long)]
+        record: bool,
+    },
+    /// Feed a recording from `run --record` through the redemption and chat
+    /// handlers without connecting to Twitch.
+    Replay {
+        /// The recording to replay.
+        file: PathBuf,
+        /// Replay this many times as fast as recorded; 0 skips the pauses.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Re-run the interactive configuration setup.
+    Setup,
+    /// Log in through Twitch and store a fresh token.
+    Login {
+        /// Log in for this channel from the `channels` setting instead of
+        /// `BROADCASTER_ID`.
+        #[arg(long)]
+        channel: Option<String>,
+    },
+    /// Revoke the stored Twitch token and remove it from disk.
+    Logout {
+        /// Log out the token of this channel from the `channels` setting.
+        #[arg(long)]
+        channel: Option<String>,
+        /// Also delete every EventSub subscription of this client id.
+        #[arg(long)]
+        delete_subscriptions: bool,
+    },
+    /// Inspect and test the sounds directory.
+    Sounds {
+        #[command(subcommand)]
+        command: SoundsCommand,
+    },
+    /// Inspect the local audio output devices.
+    Devices {
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Inspect the channel point rewards of the broadcaster.
+    Rewards {
+        #[command(subcommand)]
+        command: RewardsCommand,
+    },
+    /// Manage the EventSub subscriptions created by this client id.
+    Subscriptions {
+        #[command(subcommand)]
+        command: SubscriptionsCommand,
+    },
+    /// Inspect the configuration file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Query the redemption history.
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SoundsCommand {
+    /// List the sounds that rewards can be matched against.
+    List,
+    /// Play a sound locally as if its reward had been redeemed.
+    Test {
+        /// Name of the sound, matched like a reward title.
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DevicesCommand {
+    /// List the output devices that sounds can be routed to.
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum RewardsCommand {
+    /// List the custom rewards and whether a sound matches each of them.
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum SubscriptionsCommand {
+    /// List the EventSub subscriptions of this client id.
+    List,
+    /// Delete EventSub subscriptions.
+    Delete {
+        /// Ids of the subscriptions to delete.
+        ids: Vec<String>,
+        /// Delete every subscription of this client id.
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the configuration file and report any problems.
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommand {
+    /// List the most played sounds.
+    TopSounds {
+        /// How many sounds to list.
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+        /// Only count the last N days.
+        #[arg(long)]
+        days: Option<u64>,
+    },
+    /// List the viewers with the most redemptions.
+    TopRedeemers {
+        /// How many viewers to list.
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+        /// Only count the last N days.
+        #[arg(long)]
+        days: Option<u64>,
+    },
+    /// List the redemptions of a viewer, newest first.
+    User {
+        /// Login or user id of the viewer.
+        user: String,
+        /// How many redemptions to list.
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Delete the entries older than `HISTORY_RETENTION_DAYS` now.
+    Prune,
+}
+
+/// Start of the last `days` days in Unix seconds, or 0 for all time.
+fn since_days(days: Option<u64>) -> u64 {
+    days.map_or(0, |days| history::now().saturating_sub(days * 24 * 60 * 60))
+}
+
+/// Executes the parsed command line.
+pub async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    match cli.command.unwrap_or(Command::Run { record: false }) {
+        Command::Run { record } => {
+            ensure_config()?;
+            if record {
+                recording::start()?;
+            }
+            // Decode the sounds up front so the first redemption is instant.
+            prewarm();
+            tokio::spawn(listen_for_control_signals());
+            tokio::spawn(crate::api::serve_from_env());
+            tokio::spawn(crate::health::run_watchdog());
+            tokio::spawn(history::enforce_retention());
+            let helix = helix::connect(None).await?;
+            let channels = channels::load(helix).await?;
+            for channel in &channels {
+                tokio::spawn(channel.helix.clone().keep_token_fresh());
+            }
+            run_eventsub_ws_service(channels).await?;
+        }
+        Command::Replay { file, speed } => {
+            ensure_config()?;
+            prewarm();
+            recording::replay(&file, speed).await?;
+        }
+        Command::Setup => {
+            let path = config_path()?;
+            interactive_setup(&path)?;
+        }
+        Command::Login { channel } => {
+            ensure_config()?;
+            StoredToken::create_twitch_token(channel.as_deref()).await?;
+            println!("Login successful, token stored.");
+        }
+        Command::Logout {
+            channel,
+            delete_subscriptions,
+        } => {
+            ensure_config()?;
+            StoredToken::logout(channel.as_deref(), delete_subscriptions)
+                .await?;
+        }
+        Command::Sounds { command } => match command {
+            SoundsCommand::List => {
+                for name in sound_names() {
+                    println!("{}", name);
+                }
+            }
+            SoundsCommand::Test { name } => {
+                play_test_sound(&name);
+            }
+        },
+        Command::Devices { command } => match command {
+            DevicesCommand::List => {
+                let (devices, default) = list_output_devices()?;
+                for device in devices {
+                    let marker = if Some(&device) == default.as_ref() {
+                        " (default)"
+                    } else {
+                        ""
+                    };
+                    println!("{}{}", device, marker);
+                }
+            }
+        },
+        Command::Rewards { command } => match command {
+            RewardsCommand::List => {
+                ensure_config()?;
+                let helix = helix::connect(None).await?;
+                let broadcaster = channels::primary_broadcaster_id(&helix)?;
+                let sounds: Vec<String> = sound_names()
+                    .iter()
+                    .map(|name| name.to_lowercase())
+                    .collect();
+                for reward in get_custom_rewards(&helix, &broadcaster).await? {
+                    let has_sound =
+                        sounds.contains(&reward.title.to_lowercase());
+                    println!(
+                        "{}  {:<32} cost={:<7} enabled={:<5} paused={:<5} \
+                         sound={}",
+                        reward.id,
+                        reward.title,
+                        reward.cost,
+                        reward.is_enabled,
+                        reward.is_paused,
+                        if has_sound { "yes" } else { "no" },
+                    );
+                }
+            }
+        },
+        Command::Subscriptions { command } => {
+            ensure_config()?;
+            let helix = helix::connect(None).await?;
+            match command {
+                SubscriptionsCommand::List => {
+                    let list = list_subscriptions(&helix).await?;
+                    println!(
+                        "{} subscription(s), cost {}/{}",
+                        list.subscriptions.len(),
+                        list.total_cost,
+                        list.max_total_cost
+                    );
+                    for sub in list.subscriptions {
+                        println!(
+                            "{}  {:<10} {} v{} cost={} created={}",
+                            sub.id,
+                            sub.status,
+                            sub.event_type,
+                            sub.version,
+                            sub.cost,
+                            sub.created_at,
+                        );
+                    }
+                }
+                SubscriptionsCommand::Delete { ids, all } => {
+                    let ids = if all {
+                        list_subscriptions(&helix)
+                            .await?
+                            .subscriptions
+                            .into_iter()
+                            .map(|sub| sub.id)
+                            .collect()
+                    } else {
+                        ids
+                    };
+                    for id in ids {
+                        delete_subscription(&helix, &id).await?;
+                        println!("Deleted subscription {}", id);
+                    }
+                }
+            }
+        }
+        Command::Config { command } => match command {
+            ConfigCommand::Check => {
+                let path = config_path()?;
+                if !path.exists() {
+                    return Err(format!("No config found at {:?}", path).into());
+                }
+                dotenvy::from_path(&path)?;
+                let problems = check_config();
+                if problems.is_empty() {
+                    println!("Config at {:?} looks good.", path);
+                } else {
+                    for problem in &problems {
+                        println!("{}", problem);
+                    }
+                    return Err(format!(
+                        "{} problem(s) found in {:?}",
+                        problems.len(),
+                        path
+                    )
+                    .into());
+                }
+            }
+        },
+        Command::History { command } => {
+            ensure_config()?;
+            let history = open_from_env()?;
+            match command {
+                HistoryCommand::TopSounds { limit, days } => {
+                    for (sound, count) in
+                        history.top_sounds(since_days(days), limit)?
+                    {
+                        println!("{:>6}  {}", count, sound);
+                    }
+                }
+                HistoryCommand::TopRedeemers { limit, days } => {
+                    for (user, count) in
+                        history.top_redeemers(since_days(days), limit)?
+                    {
+                        println!("{:>6}  {}", count, user);
+                    }
+                }
+                HistoryCommand::User { user, limit } => {
+                    for entry in history.for_user(&user, limit)? {
+                        let at = chrono::DateTime::from_timestamp(
+                            entry.at as i64,
+                            0,
+                        )
+                        .map(|at| at.to_rfc3339())
+                        .unwrap_or_default();
+                        println!(
+                            "{}  {:<9} {:<24} sound={} input={:?}",
+                            at,
+                            entry.outcome,
+                            entry.reward_title,
+                            entry.sound.as_deref().unwrap_or("-"),
+                            entry.input,
+                        );
+                    }
+                }
+                HistoryCommand::Prune => {
+                    let Some(retention) = history::retention() else {
+                        return Err("HISTORY_RETENTION_DAYS is not set".into());
+                    };
+                    let before =
+                        history::now().saturating_sub(retention.as_secs());
+                    let count = history.prune(before)?;
+                    println!("Deleted {} entries.", count);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_nested_subcommands() {
+        let cli =
+            Cli::try_parse_from(["twitch-soundbot", "sounds", "test", "Boom"])
+                .unwrap();
+        match cli.command {
+            Some(Command::Sounds {
+                command: SoundsCommand::Test { name },
+            }) => assert_eq!(name, "Boom"),
+            _ => panic!("unexpected command"),
+        }
+    }
+
+    #[test]
+    fn test_defaults_to_run_without_subcommand() {
+        let cli = Cli::try_parse_from(["twitch-soundbot"]).unwrap();
+        assert!(cli.command.is_none());
+    }
+}
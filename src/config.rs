@@ -1,13 +1,32 @@
-use dirs;
+use crate::channels::ChannelSettings;
+use crate::chat::ChatSettings;
+use crate::pool::PoolStrategy;
+use crate::redemption::{ModerationSettings, Role};
+use crate::tts::{TtsMode, TtsSettings};
 use rand::Rng;
 use rand::SeedableRng;
 use rand_chacha::ChaCha20Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use url::Url;
+
+/// Keys every config file is expected to define.
+const REQUIRED_KEYS: [&str; 6] = [
+    "CLIENT_ID",
+    "CLIENT_SECRET",
+    "REDIRECT_URI",
+    "BROADCASTER_ID",
+    "BIND_ADDRESS",
+    "EVENTSUB_SECRET",
+];
 
 /// Returns the path to the configuration file.
-fn config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+pub fn config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
     let mut path = dirs::config_dir().ok_or("No config directory")?;
     path.push("twitch-soundbot");
     fs::create_dir_all(&path)?;
@@ -15,10 +34,115 @@ fn config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
     Ok(path)
 }
 
-/// Prompts the user for configuration and writes it to disk.
-fn interactive_setup(path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    println!("No config found. Let's set it up.");
+/// Per-sound options from `settings.json`.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct SoundSettings {
+    /// Output device this sound is played on.
+    pub device: Option<String>,
+    /// Linear gain applied on top of the master volume.
+    pub gain: Option<f32>,
+    /// Image or GIF shown on the overlay while the sound plays: a file in
+    /// `OVERLAY_DIR` or a URL.
+    pub image: Option<String>,
+    /// Only viewers with one of these roles may redeem the reward. Empty
+    /// allows everyone, unless `allowed_users` is set.
+    pub allowed_roles: Vec<Role>,
+    /// Viewers (login or user id) who may redeem the reward regardless of
+    /// `allowed_roles`.
+    pub allowed_users: Vec<String>,
+    /// Plays the sound the viewer names in their message instead of the one
+    /// matching the reward title.
+    pub sound_from_input: bool,
+    /// Whether the reward speaks the viewer's message, after its sound or
+    /// instead of it.
+    pub tts: Option<TtsMode>,
+    /// Text-to-speech voice for this reward, overriding `TTS_VOICE`.
+    pub voice: Option<String>,
+    /// Sounds with a higher priority duck lower ones while they play.
+    /// Defaults to 0.
+    pub priority: Option<i32>,
+    /// Milliseconds over which the sound fades in.
+    pub fade_in_ms: Option<u64>,
+    /// Milliseconds over which the sound fades out at its end, or when it is
+    /// cut off by `MAX_CLIP_SECS`.
+    pub fade_out_ms: Option<u64>,
+    /// How a pool (a folder of clips) picks its next clip.
+    pub strategy: Option<PoolStrategy>,
+    /// Weights of the clips in a pool for the weighted strategy, keyed by
+    /// clip name (case-insensitive). Clips without a weight count as 1.
+    pub weights: HashMap<String, f32>,
+}
+
+/// Structured settings that do not fit into `.env`, read from the optional
+/// `settings.json` next to it.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Settings {
+    /// Options per sound, keyed by sound name (case-insensitive).
+    pub sounds: HashMap<String, SoundSettings>,
+    /// Output device per event type, e.g. `"redemption"` or `"test"`.
+    pub devices: HashMap<String, String>,
+    /// Text-to-speech options shared by all rewards.
+    pub tts: TtsSettings,
+    /// Who may trigger sounds and what they may type.
+    pub moderation: ModerationSettings,
+    /// Chat commands and replies.
+    pub chat: ChatSettings,
+    /// More channels to play alerts for, besides `BROADCASTER_ID`.
+    pub channels: Vec<ChannelSettings>,
+}
+
+impl Settings {
+    /// Options of the named sound, matched case-insensitively.
+    pub fn sound(&self, name: &str) -> Option<&SoundSettings> {
+        self.sounds
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, settings)| settings)
+    }
+}
+
+/// Returns the path of a file stored next to the configuration file.
+pub fn data_path(file: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = config_path()?;
+    path.set_file_name(file);
+    Ok(path)
+}
 
+/// Returns the path to the structured settings file.
+pub fn settings_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    data_path("settings.json")
+}
+
+/// Reads `settings.json`. A missing file yields the defaults; a broken one
+/// is reported and ignored so the bot still starts.
+pub fn load_settings() -> Settings {
+    match settings_path() {
+        Ok(path) => load_settings_from(&path),
+        Err(_) => Settings::default(),
+    }
+}
+
+/// Reads a settings file in the format of `settings.json`, with the same
+/// fallback to the defaults.
+pub fn load_settings_from(path: &Path) -> Settings {
+    let Ok(data) = fs::read_to_string(path) else {
+        return Settings::default();
+    };
+    match serde_json::from_str(&data) {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!(path = ?path, "Ignoring invalid settings file: {}", e);
+            Settings::default()
+        }
+    }
+}
+
+/// Prompts the user for configuration and writes it to disk.
+pub fn interactive_setup(
+    path: &PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
     fn prompt(msg: &str) -> Result<String, Box<dyn std::error::Error>> {
         print!("{}: ", msg);
         io::stdout().flush()?;
@@ -87,7 +211,29 @@ fn interactive_setup(path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     );
 
     fs::write(path, env_content)?;
-    println!("Config written to {:?}", path);
+    info!(path = ?path, "Config written.");
+    Ok(())
+}
+
+/// Sets `key` to `value` in the config file at `path`, replacing an earlier
+/// value or appending it.
+pub fn set_config_value(
+    path: &Path,
+    key: &str,
+    value: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path)?;
+    let prefix = format!("{}=", key);
+    let line = format!("{}{}", prefix, value);
+    let mut lines: Vec<&str> = content.lines().collect();
+    match lines
+        .iter()
+        .position(|l| l.trim_start().starts_with(&prefix))
+    {
+        Some(i) => lines[i] = &line,
+        None => lines.push(&line),
+    }
+    fs::write(path, lines.join("\n") + "\n")?;
     Ok(())
 }
 
@@ -97,11 +243,60 @@ pub fn ensure_config() -> Result<(), Box<dyn std::error::Error>> {
     if path.exists() {
         dotenvy::from_path(&path)?;
     } else {
+        println!("No config found. Let's set it up.");
         interactive_setup(&path)?;
+        dotenvy::from_path(&path)?;
     }
+    crate::logging::register_config_secrets();
     Ok(())
 }
 
+/// Loads the config file into the environment if it exists, without
+/// prompting. Used before logging is set up so `LOG_*` settings apply.
+pub fn load_existing_config() {
+    if let Ok(path) = config_path() {
+        if path.exists() {
+            dotenvy::from_path(&path).ok();
+            crate::logging::register_config_secrets();
+        }
+    }
+}
+
+/// Validates the loaded configuration and returns a description of every
+/// problem found. An empty list means the config looks usable.
+pub fn check_config() -> Vec<String> {
+    let mut problems = Vec::new();
+    for key in REQUIRED_KEYS {
+        match std::env::var(key) {
+            Ok(value) if !value.trim().is_empty() => {}
+            _ => problems.push(format!("{} is missing or empty", key)),
+        }
+    }
+
+    if let Ok(uri) = std::env::var("REDIRECT_URI") {
+        if Url::parse(&uri).is_err() {
+            problems.push(format!("REDIRECT_URI is not a valid URL: {}", uri));
+        }
+    }
+    if let Ok(addr) = std::env::var("BIND_ADDRESS") {
+        if addr.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "BIND_ADDRESS is not a valid socket address: {}",
+                addr
+            ));
+        }
+    }
+    if let Ok(secret) = std::env::var("EVENTSUB_SECRET") {
+        if secret.len() < 10 || secret.len() > 100 {
+            problems.push(
+                "EVENTSUB_SECRET must be between 10 and 100 characters"
+                    .to_string(),
+            );
+        }
+    }
+    problems
+}
+
 #[cfg(test)]
 mod config_tests {
     #[test]
@@ -150,4 +345,34 @@ EVENTSUB_SECRET=another_32_char_secret_value
             "another_32_char_secret_value"
         );
     }
+
+    #[test]
+    fn test_sets_config_values_in_place() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let path = temp_file.path();
+        std::fs::write(path, "BROADCASTER_NUMERIC_ID=1\nCLIENT_ID=abc\n")
+            .unwrap();
+
+        super::set_config_value(path, "BROADCASTER_NUMERIC_ID", "2").unwrap();
+        super::set_config_value(path, "LOG_LEVEL", "debug").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "BROADCASTER_NUMERIC_ID=2\nCLIENT_ID=abc\nLOG_LEVEL=debug\n"
+        );
+    }
+
+    #[test]
+    fn test_parses_settings_and_matches_sounds_ignoring_case() {
+        let settings: super::Settings = serde_json::from_str(
+            r#"{
+                "sounds": { "CoolSound": { "device": "Virtual Cable" } },
+                "devices": { "redemption": "Speakers" }
+            }"#,
+        )
+        .unwrap();
+        let sound = settings.sound("coolsound").unwrap();
+        assert_eq!(sound.device.as_deref(), Some("Virtual Cable"));
+        assert_eq!(settings.devices["redemption"], "Speakers");
+        assert!(settings.sound("other").is_none());
+    }
 }
@@ -1,17 +1,33 @@
-use futures_util::StreamExt;
-use reqwest;
-use serde::Serialize;
-use serde_json::Value;
-use std::env;
+use crate::helix::Helix;
+use crate::logging::format_message;
+use crate::metrics;
+use crate::redemption::Role;
+use crate::shutdown::{self, DrainMode, COORDINATOR};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::error::Error;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_tungstenite::connect_async;
-use twitch_oauth2::TwitchToken; // for token().secret()
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, warn};
 
 /// Struct for the condition in the subscription payload.
 #[derive(Serialize)]
 struct Condition {
     broadcaster_user_id: String,
+    /// The user reading chat, for chat subscriptions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
 }
 
 /// Struct for the websocket transport in the subscription payload.
@@ -32,6 +48,76 @@ struct WsSubscriptionPayload {
     transport: WsTransport,
 }
 
+/// An EventSub subscription as returned by Get EventSub Subscriptions.
+#[derive(Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub status: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub version: String,
+    pub created_at: String,
+    pub cost: u32,
+}
+
+impl Subscription {
+    /// Whether the subscription can no longer deliver events and should be
+    /// deleted. Twitch keeps failed subscriptions around (and, for webhooks,
+    /// counts them against the cost limit) until they are removed.
+    pub fn is_stale(&self) -> bool {
+        !matches!(
+            self.status.as_str(),
+            "enabled" | "webhook_callback_verification_pending"
+        )
+    }
+}
+
+/// Page of subscriptions plus the cost totals reported by Twitch.
+#[derive(Deserialize)]
+struct SubscriptionPage {
+    data: Vec<Subscription>,
+    #[serde(default)]
+    total_cost: u32,
+    #[serde(default)]
+    max_total_cost: u32,
+    #[serde(default)]
+    pagination: Pagination,
+}
+
+/// All subscriptions of this client id with the cost totals from Twitch.
+pub struct SubscriptionList {
+    pub subscriptions: Vec<Subscription>,
+    pub total_cost: u32,
+    pub max_total_cost: u32,
+}
+
+#[derive(Deserialize, Default)]
+struct Pagination {
+    cursor: Option<String>,
+}
+
+/// A channel points custom reward as returned by Get Custom Reward.
+#[derive(Deserialize)]
+pub struct CustomReward {
+    pub id: String,
+    pub title: String,
+    pub cost: u64,
+    pub is_enabled: bool,
+    pub is_paused: bool,
+}
+
+#[derive(Deserialize)]
+struct CustomRewardList {
+    data: Vec<CustomReward>,
+}
+
+/// Response of the Helix endpoints that list users, when only whether the
+/// list is empty matters.
+#[derive(Deserialize)]
+struct UserList {
+    data: Vec<Value>,
+}
+
 /// Helper: Given a JSON text, attempt to extract a session_id
 /// This expects the message structure to have:
 /// - "metadata.message_type" == "session_welcome"
@@ -56,34 +142,134 @@ pub fn extract_session_id(text: &str) -> Option<String> {
     None
 }
 
-/// Connects to Twitch’s EventSub WebSocket endpoint and waits.
-/// Returns the WebSocket stream and the extracted session_id.
-pub async fn connect_eventsub_ws() -> Result<
-    (
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-        String,
-    ),
-    Box<dyn Error>,
-> {
-    // Use the production WebSocket endpoint per Twitch docs.
-    let ws_url = "wss://eventsub.wss.twitch.tv/ws";
+/// Helper: Given a welcome message, extract how long Twitch may stay silent
+/// before the connection should be considered lost, from
+/// "payload.session.keepalive_timeout_seconds".
+pub fn extract_keepalive_timeout(text: &str) -> Option<Duration> {
+    let v: Value = serde_json::from_str(text).ok()?;
+    v.pointer("/payload/session/keepalive_timeout_seconds")
+        .and_then(|s| s.as_u64())
+        .map(Duration::from_secs)
+}
+
+/// Helper: Given a `session_reconnect` message, extract the URL to connect
+/// to at "payload.session.reconnect_url".
+pub fn extract_reconnect_url(text: &str) -> Option<String> {
+    let v: Value = serde_json::from_str(text).ok()?;
+    if v.pointer("/metadata/message_type").and_then(|t| t.as_str())
+        != Some("session_reconnect")
+    {
+        return None;
+    }
+    v.pointer("/payload/session/reconnect_url")
+        .and_then(|u| u.as_str())
+        .map(|u| u.to_string())
+}
+
+/// The production WebSocket endpoint per Twitch docs.
+const EVENTSUB_WS_URL: &str = "wss://eventsub.wss.twitch.tv/ws";
+
+/// Keepalive timeout used if the welcome message does not carry one.
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(10);
+
+/// Extra time allowed past the keepalive timeout before reconnecting.
+const KEEPALIVE_GRACE: Duration = Duration::from_secs(5);
+
+/// Wait after the first failed reconnect; doubled after every further one.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnect attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How many sessions the channels are spread over, for the readiness check.
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many of the sessions are open and welcomed.
+static SESSIONS_CONNECTED: AtomicUsize = AtomicUsize::new(0);
+
+/// How many of the sessions have their subscriptions in place.
+static SESSIONS_SUBSCRIBED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether `count` covers every session.
+fn every_session(count: &AtomicUsize) -> bool {
+    let sessions = SESSIONS.load(Ordering::SeqCst);
+    sessions > 0 && count.load(Ordering::SeqCst) == sessions
+}
+
+/// Whether every EventSub session is open and welcomed.
+pub fn session_connected() -> bool {
+    every_session(&SESSIONS_CONNECTED)
+}
+
+/// Whether redemptions are being delivered to every session.
+pub fn subscription_active() -> bool {
+    every_session(&SESSIONS_SUBSCRIBED)
+}
+
+/// State of one session, kept in step with the session counters. Dropping
+/// it counts the session as closed.
+#[derive(Default)]
+struct SessionState {
+    connected: bool,
+    subscribed: bool,
+}
+
+impl SessionState {
+    fn set_connected(&mut self, connected: bool) {
+        update_count(&SESSIONS_CONNECTED, &mut self.connected, connected);
+    }
+
+    fn set_subscribed(&mut self, subscribed: bool) {
+        update_count(&SESSIONS_SUBSCRIBED, &mut self.subscribed, subscribed);
+    }
+}
+
+impl Drop for SessionState {
+    fn drop(&mut self) {
+        self.set_connected(false);
+        self.set_subscribed(false);
+    }
+}
+
+/// Sets `flag` to `value`, counting the change in `count`.
+fn update_count(count: &AtomicUsize, flag: &mut bool, value: bool) {
+    if *flag == value {
+        return;
+    }
+    if value {
+        count.fetch_add(1, Ordering::SeqCst);
+    } else {
+        count.fetch_sub(1, Ordering::SeqCst);
+    }
+    *flag = value;
+}
+
+/// Connects to an EventSub WebSocket endpoint and waits for its welcome.
+/// Returns the WebSocket stream, the extracted session_id and the keepalive
+/// timeout of the session.
+pub async fn connect_eventsub_ws(
+    ws_url: &str,
+) -> Result<(WsStream, String, Duration), Box<dyn Error>> {
     let (ws_stream, _) = connect_async(ws_url).await?;
-    println!("Connected to Twitch EventSub WebSocket endpoint.");
+    info!("Connected to Twitch EventSub WebSocket endpoint.");
 
     let mut stream = ws_stream;
     let mut session_id = None;
+    let mut keepalive = DEFAULT_KEEPALIVE;
     // Wait up to 10 seconds for a welcome message.
     for _ in 0..10 {
         if let Some(msg) = stream.next().await {
             let msg = msg?;
             if msg.is_text() {
                 let text = msg.to_text()?;
-                //TODO: Create a format message function.
-                //println!("Received message: {}", text);
+                debug!(message = %format_message(text), "Received message");
+                crate::recording::record(text);
                 if let Some(sid) = extract_session_id(text) {
                     session_id = Some(sid);
+                    keepalive =
+                        extract_keepalive_timeout(text).unwrap_or(keepalive);
                     break;
                 }
             }
@@ -91,133 +277,767 @@ pub async fn connect_eventsub_ws() -> Result<
         tokio::time::sleep(Duration::from_secs(1)).await;
     }
     if let Some(sid) = session_id {
-        Ok((stream, sid))
+        Ok((stream, sid, keepalive))
     } else {
         Err("Failed to receive session welcome message".into())
     }
 }
 
-/// Looks up the numeric broadcaster ID from Twitch given a username.
-/// This calls the Get Users API and returns the numeric user ID.
-async fn get_numeric_broadcaster_id(
-    username: &str,
-    token: &str,
+/// Type of the channel point redemption subscription.
+const REDEMPTION_EVENT: &str =
+    "channel.channel_points_custom_reward_redemption.add";
+
+/// Type of the chat message subscription.
+const CHAT_MESSAGE_EVENT: &str = "channel.chat.message";
+
+/// Registers a websocket subscription for redemptions with Twitch using the
+/// provided session_id. Returns the id of the created subscription.
+pub async fn register_ws_subscription(
+    helix: &Helix,
+    broadcaster_numeric_id: &str,
+    session_id: &str,
 ) -> Result<String, Box<dyn Error>> {
-    let client = reqwest::Client::new();
-    let client_id = env::var("CLIENT_ID")?;
-    let url = format!("https://api.twitch.tv/helix/users?login={}", username);
-    let res = client
-        .get(&url)
-        .header("Client-ID", client_id)
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
-        .await?;
-    if !res.status().is_success() {
-        return Err(format!(
-            "Failed to fetch broadcaster id: {}",
-            res.status()
-        )
-        .into());
-    }
-    let json: Value = res.json().await?;
-    if let Some(data) = json.get("data") {
-        if let Some(user) = data.as_array().and_then(|arr| arr.get(0)) {
-            if let Some(id) = user.get("id").and_then(|v| v.as_str()) {
-                return Ok(id.to_string());
-            }
-        }
-    }
-    Err("No broadcaster id found".into())
+    let condition = Condition {
+        broadcaster_user_id: broadcaster_numeric_id.to_string(),
+        user_id: None,
+    };
+    subscribe(helix, REDEMPTION_EVENT, condition, session_id).await
 }
 
-/// Registers a websocket subscription with Twitch using the provided session_id.
-pub async fn register_ws_subscription(
-    token: &str,
+/// Registers a websocket subscription for the broadcaster's chat messages,
+/// read as the client's user. Needs the `user:read:chat` scope.
+pub async fn register_chat_subscription(
+    helix: &Helix,
     broadcaster_numeric_id: &str,
     session_id: &str,
-) -> Result<(), Box<dyn Error>> {
-    let client = reqwest::Client::new();
-    let payload = WsSubscriptionPayload {
-        event_type: "channel.channel_points_custom_reward_redemption.add"
-            .to_string(),
+) -> Result<String, Box<dyn Error>> {
+    let condition = Condition {
+        broadcaster_user_id: broadcaster_numeric_id.to_string(),
+        user_id: Some(helix.user_id().to_string()),
+    };
+    subscribe(helix, CHAT_MESSAGE_EVENT, condition, session_id).await
+}
+
+/// Registers a websocket subscription to `event_type`. Returns the id of the
+/// created subscription.
+async fn subscribe(
+    helix: &Helix,
+    event_type: &str,
+    condition: Condition,
+    session_id: &str,
+) -> Result<String, Box<dyn Error>> {
+    let payload = serde_json::to_value(WsSubscriptionPayload {
+        event_type: event_type.to_string(),
         version: "1".to_string(),
-        condition: Condition {
-            broadcaster_user_id: broadcaster_numeric_id.to_string(),
-        },
+        condition,
         transport: WsTransport {
             method: "websocket".to_string(),
             session_id: session_id.to_string(),
         },
+    })?;
+
+    let page: SubscriptionPage = helix
+        .request(Method::POST, "eventsub/subscriptions", &[], Some(&payload))
+        .await
+        .map_err(|e| format!("Failed to register subscription: {}", e))?;
+    let sub = page
+        .data
+        .into_iter()
+        .next()
+        .ok_or("Subscription response contained no subscription")?;
+    info!(
+        subscription_id = %sub.id,
+        event_type,
+        total_cost = page.total_cost,
+        max_total_cost = page.max_total_cost,
+        "Successfully registered websocket subscription."
+    );
+    Ok(sub.id)
+}
+
+/// Lists every EventSub subscription created by this client id, following
+/// pagination until all pages have been read.
+pub async fn list_subscriptions(
+    helix: &Helix,
+) -> Result<SubscriptionList, Box<dyn Error>> {
+    let mut list = SubscriptionList {
+        subscriptions: Vec::new(),
+        total_cost: 0,
+        max_total_cost: 0,
     };
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut query = Vec::new();
+        if let Some(after) = &cursor {
+            query.push(("after", after.as_str()));
+        }
+        let page: SubscriptionPage = helix
+            .request(Method::GET, "eventsub/subscriptions", &query, None)
+            .await
+            .map_err(|e| format!("Failed to list subscriptions: {}", e))?;
+        list.subscriptions.extend(page.data);
+        list.total_cost = page.total_cost;
+        list.max_total_cost = page.max_total_cost;
+        match page.pagination.cursor {
+            Some(next) if !next.is_empty() => cursor = Some(next),
+            _ => break,
+        }
+    }
+    Ok(list)
+}
 
-    let response = client
-        .post("https://api.twitch.tv/helix/eventsub/subscriptions")
-        .header("Client-ID", env::var("CLIENT_ID")?)
-        .header("Authorization", format!("Bearer {}", token))
-        .json(&payload)
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        println!("Successfully registered websocket subscription.");
-        Ok(())
-    } else {
-        let text = response.text().await?;
-        Err(format!("Failed to register subscription: {}", text).into())
+/// Deletes a single EventSub subscription by id. A subscription that is
+/// already gone counts as deleted: Twitch removes WebSocket subscriptions
+/// itself when their session closes.
+pub async fn delete_subscription(
+    helix: &Helix,
+    subscription_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let deleted = helix
+        .execute(
+            Method::DELETE,
+            "eventsub/subscriptions",
+            &[("id", subscription_id)],
+            None,
+        )
+        .await;
+    match deleted {
+        Ok(()) => Ok(()),
+        Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => Ok(()),
+        Err(e) => Err(format!("Failed to delete subscription: {}", e).into()),
     }
 }
 
-/// Connects to the Twitch EventSub WebSocket, registers a subscription using
-/// the session_id, and processes incoming messages. Redemption events are
-/// delegated to the redemption handler.
-pub async fn run_eventsub_ws_service(
-    token: &twitch_oauth2::UserToken,
+/// Deletes every subscription of this client id that can no longer deliver
+/// events and reports the cost totals Twitch returned.
+pub async fn prune_subscriptions(helix: &Helix) -> Result<(), Box<dyn Error>> {
+    let list = list_subscriptions(helix).await?;
+    info!(
+        count = list.subscriptions.len(),
+        total_cost = list.total_cost,
+        max_total_cost = list.max_total_cost,
+        "Found existing subscriptions."
+    );
+    for sub in list.subscriptions.iter().filter(|sub| sub.is_stale()) {
+        match delete_subscription(helix, &sub.id).await {
+            Ok(()) => info!(
+                subscription_id = %sub.id,
+                event_type = %sub.event_type,
+                status = %sub.status,
+                "Pruned stale subscription."
+            ),
+            Err(e) => warn!(
+                subscription_id = %sub.id,
+                "Failed to prune subscription: {}",
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the subscriptions this process created. Failures are reported but
+/// do not stop the remaining deletions.
+pub async fn delete_own_subscriptions(
+    helix: &Helix,
+    subscription_ids: &[String],
+) {
+    for id in subscription_ids {
+        match delete_subscription(helix, id).await {
+            Ok(()) => info!(subscription_id = %id, "Deleted subscription."),
+            Err(e) => warn!(
+                subscription_id = %id,
+                "Failed to delete subscription: {}",
+                e
+            ),
+        }
+    }
+}
+
+/// Lists the custom channel point rewards of the given broadcaster.
+pub async fn get_custom_rewards(
+    helix: &Helix,
+    broadcaster_numeric_id: &str,
+) -> Result<Vec<CustomReward>, Box<dyn Error>> {
+    let rewards: CustomRewardList = helix
+        .request(
+            Method::GET,
+            "channel_points/custom_rewards",
+            &[("broadcaster_id", broadcaster_numeric_id)],
+            None,
+        )
+        .await
+        .map_err(|e| format!("Failed to fetch custom rewards: {}", e))?;
+    Ok(rewards.data)
+}
+
+/// Checks whether a user has a role in the broadcaster's channel. Looking
+/// up subscribers, VIPs and moderators needs the `channel:read:subscriptions`,
+/// `channel:read:vips` and `moderation:read` scopes respectively.
+pub async fn has_role(
+    helix: &Helix,
+    broadcaster_numeric_id: &str,
+    user_id: &str,
+    role: Role,
+) -> Result<bool, Box<dyn Error>> {
+    let endpoint = match role {
+        Role::Broadcaster => return Ok(user_id == broadcaster_numeric_id),
+        Role::Moderator => "moderation/moderators",
+        Role::Vip => "channels/vips",
+        Role::Subscriber => "subscriptions",
+    };
+    let users: UserList = helix
+        .request(
+            Method::GET,
+            endpoint,
+            &[
+                ("broadcaster_id", broadcaster_numeric_id),
+                ("user_id", user_id),
+            ],
+            None,
+        )
+        .await
+        .map_err(|e| format!("Failed to look up {:?} role: {}", role, e))?;
+    Ok(!users.data.is_empty())
+}
+
+/// Marks a redemption as `FULFILLED` or `CANCELED`; cancelling refunds the
+/// viewer's points. Twitch only allows this for rewards created by the same
+/// client id.
+pub async fn update_redemption_status(
+    helix: &Helix,
+    broadcaster_numeric_id: &str,
+    reward_id: &str,
+    redemption_id: &str,
+    status: &str,
 ) -> Result<(), Box<dyn Error>> {
-    // Get the broadcaster identifier from env vars.
-    let provided_broadcaster = env::var("BROADCASTER_ID")?;
-    let token_str = token.token().secret();
+    helix
+        .execute(
+            Method::PATCH,
+            "channel_points/custom_rewards/redemptions",
+            &[
+                ("broadcaster_id", broadcaster_numeric_id),
+                ("reward_id", reward_id),
+                ("id", redemption_id),
+            ],
+            Some(&json!({ "status": status })),
+        )
+        .await
+        .map_err(|e| format!("Failed to update redemption: {}", e).into())
+}
+
+/// Sends a chat message to the broadcaster's channel as the client's user,
+/// optionally as a reply. Needs the `user:write:chat` scope.
+pub async fn send_chat_message(
+    helix: &Helix,
+    broadcaster_numeric_id: &str,
+    message: &str,
+    reply_parent_message_id: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut body = json!({
+        "broadcaster_id": broadcaster_numeric_id,
+        "sender_id": helix.user_id(),
+        "message": message,
+    });
+    if let Some(parent) = reply_parent_message_id {
+        body["reply_parent_message_id"] = json!(parent);
+    }
+    let sent: Value = helix
+        .request(Method::POST, "chat/messages", &[], Some(&body))
+        .await
+        .map_err(|e| format!("Failed to send chat message: {}", e))?;
+    // Twitch answers 200 even when it drops the message, e.g. for AutoMod.
+    if sent.pointer("/data/0/is_sent").and_then(|s| s.as_bool()) == Some(false)
+    {
+        let reason = sent
+            .pointer("/data/0/drop_reason/message")
+            .and_then(|r| r.as_str())
+            .unwrap_or("unknown reason");
+        return Err(format!("Chat message was dropped: {}", reason).into());
+    }
+    Ok(())
+}
+
+/// A channel to receive events for, with the client of the user its
+/// subscriptions are created by.
+#[derive(Clone)]
+pub struct ChannelSubscriber {
+    pub broadcaster_id: String,
+    pub helix: Helix,
+    /// Whether chat messages are subscribed to as well.
+    pub chat: bool,
+}
+
+impl ChannelSubscriber {
+    /// How many subscriptions the channel takes on a session.
+    fn subscriptions(&self) -> usize {
+        1 + usize::from(self.chat)
+    }
+}
 
-    // Look up the numeric broadcaster ID from Twitch.
-    let numeric_broadcaster_id =
-        get_numeric_broadcaster_id(&provided_broadcaster, token_str).await?;
-    println!("Numeric broadcaster ID: {}", numeric_broadcaster_id);
+/// Twitch allows this many enabled subscriptions on one WebSocket session.
+const MAX_SESSION_SUBSCRIPTIONS: usize = 300;
+
+/// Twitch allows this many WebSocket sessions per user.
+const MAX_USER_SESSIONS: usize = 3;
+
+/// Spreads channels over sessions. Channels whose subscriptions are created
+/// by the same user share sessions of up to `MAX_SESSION_SUBSCRIPTIONS`
+/// subscriptions, where `user` tells who creates a channel's subscriptions
+/// and `cost` how many it takes. A user needing more than
+/// `MAX_USER_SESSIONS` sessions is an error.
+fn plan_sessions<T>(
+    channels: Vec<T>,
+    user: impl Fn(&T) -> String,
+    cost: impl Fn(&T) -> usize,
+) -> Result<Vec<Vec<T>>, Box<dyn Error>> {
+    let mut sessions: Vec<(String, usize, Vec<T>)> = Vec::new();
+    for channel in channels {
+        let (user, cost) = (user(&channel), cost(&channel));
+        let open = sessions.iter_mut().find(|(u, used, _)| {
+            *u == user && used + cost <= MAX_SESSION_SUBSCRIPTIONS
+        });
+        match open {
+            Some((_, used, members)) => {
+                *used += cost;
+                members.push(channel);
+            }
+            None => {
+                let count =
+                    sessions.iter().filter(|(u, ..)| *u == user).count();
+                if count == MAX_USER_SESSIONS {
+                    return Err(format!(
+                        "The channels of user {} need more than {} EventSub \
+                         sessions.",
+                        user, MAX_USER_SESSIONS
+                    )
+                    .into());
+                }
+                sessions.push((user, cost, vec![channel]));
+            }
+        }
+    }
+    Ok(sessions
+        .into_iter()
+        .map(|(_, _, members)| members)
+        .collect())
+}
+
+/// Registers the subscriptions of every channel on a session: redemptions,
+/// and chat messages where chat is enabled. Returns the ids of the created
+/// subscriptions per channel; if one fails, the ones created so far are
+/// deleted again.
+async fn subscribe_channels(
+    channels: &[ChannelSubscriber],
+    session_id: &str,
+) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let mut subscriptions = Vec::new();
+    for channel in channels {
+        let mut ids = Vec::new();
+        let result = subscribe_channel(channel, session_id, &mut ids).await;
+        subscriptions.push(ids);
+        if let Err(e) = result {
+            unsubscribe_channels(channels, &subscriptions).await;
+            return Err(e);
+        }
+    }
+    Ok(subscriptions)
+}
+
+/// Registers the subscriptions of one channel, adding their ids to `ids`
+/// as they are created.
+async fn subscribe_channel(
+    channel: &ChannelSubscriber,
+    session_id: &str,
+    ids: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let helix = &channel.helix;
+    ids.push(
+        register_ws_subscription(helix, &channel.broadcaster_id, session_id)
+            .await?,
+    );
+    if channel.chat {
+        ids.push(
+            register_chat_subscription(
+                helix,
+                &channel.broadcaster_id,
+                session_id,
+            )
+            .await?,
+        );
+    }
+    Ok(())
+}
+
+/// Deletes the subscriptions `subscribe_channels` created.
+async fn unsubscribe_channels(
+    channels: &[ChannelSubscriber],
+    subscriptions: &[Vec<String>],
+) {
+    for (channel, ids) in channels.iter().zip(subscriptions) {
+        delete_own_subscriptions(&channel.helix, ids).await;
+    }
+}
+
+/// The client of the channel an event belongs to.
+fn helix_for<'a>(
+    channels: &'a [ChannelSubscriber],
+    payload: &Value,
+) -> &'a Helix {
+    let broadcaster_id = payload
+        .pointer("/event/broadcaster_user_id")
+        .and_then(|id| id.as_str());
+    let channel = channels
+        .iter()
+        .find(|c| Some(c.broadcaster_id.as_str()) == broadcaster_id)
+        .unwrap_or(&channels[0]);
+    &channel.helix
+}
+
+/// Hands an EventSub message to its handler: redemptions and chat messages
+/// are handled in their own task, acting through the client `helix` picks
+/// for the event's payload. Returns the task, if one was started. Shared by
+/// the live sessions and replays.
+pub fn dispatch_message(
+    event: &Value,
+    helix: impl FnOnce(&Value) -> Helix,
+) -> Option<JoinHandle<()>> {
+    if event
+        .pointer("/metadata/message_type")
+        .and_then(|t| t.as_str())
+        == Some("notification")
+    {
+        let subscription_type = event
+            .pointer("/metadata/subscription_type")
+            .and_then(|t| t.as_str())
+            .unwrap_or("unknown");
+        metrics::NOTIFICATIONS.inc_with(subscription_type);
+    }
+    // Look inside the "payload" object
+    let payload = event.get("payload")?;
+    let event_type = payload.pointer("/subscription/type")?.as_str()?;
+    if event_type != REDEMPTION_EVENT && event_type != CHAT_MESSAGE_EVENT {
+        return None;
+    }
+    let guard = COORDINATOR.accept_event()?;
+    let helix = helix(payload);
+    let payload = payload.clone();
+    let redemption = event_type == REDEMPTION_EVENT;
+    Some(tokio::spawn(async move {
+        if redemption {
+            crate::redemption::dispatch(payload, helix).await;
+        } else {
+            crate::chat::handle_message(payload, helix).await;
+        }
+        drop(guard);
+    }))
+}
+
+/// Wait after `failures` failed connection attempts in a row.
+fn reconnect_delay(failures: u32) -> Duration {
+    RECONNECT_DELAY
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_RECONNECT_DELAY)
+}
+
+/// Runs `attempt` until it succeeds, waiting `delay(failures)` after each
+/// failure. Returns `None` if `stop` fires while waiting; a running attempt
+/// is never abandoned, so it cannot leave subscriptions behind.
+async fn retry<T, F, Fut>(
+    what: &str,
+    delay: impl Fn(u32) -> Duration,
+    stop: &mut watch::Receiver<bool>,
+    mut attempt: F,
+) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    let mut failures = 0;
+    loop {
+        let e = match attempt().await {
+            Ok(value) => return Some(value),
+            Err(e) => e.to_string(),
+        };
+        failures += 1;
+        let wait = delay(failures);
+        warn!(
+            wait_secs = wait.as_secs_f32(),
+            "Failed to {}, retrying: {}", what, e
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = stop.changed() => return None,
+        }
+    }
+}
+
+/// Opens a new session at the EventSub endpoint and subscribes `channels`
+/// on it. Returns the stream, session id, keepalive timeout and the ids of
+/// the created subscriptions.
+async fn open_session(
+    channels: &[ChannelSubscriber],
+) -> Result<(WsStream, String, Duration, Vec<Vec<String>>), Box<dyn Error>> {
+    let (ws_stream, session_id, keepalive) =
+        connect_eventsub_ws(EVENTSUB_WS_URL).await?;
+    let subscriptions = subscribe_channels(channels, &session_id).await?;
+    Ok((ws_stream, session_id, keepalive, subscriptions))
+}
+
+/// Receives the events of `channels` on one WebSocket session. When the
+/// connection goes silent, fails or is closed, a new session is opened and
+/// subscribed, retrying with backoff until it works; `session_reconnect`
+/// requests are followed without subscribing again. Returns after deleting
+/// the subscriptions and closing the connection once `stop` fires, or with
+/// an error if the first session could not be opened.
+async fn run_session(
+    channels: &[ChannelSubscriber],
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error>> {
+    let mut state = SessionState::default();
 
     // Connect to the WebSocket endpoint and obtain the session_id.
-    let (ws_stream, session_id) = connect_eventsub_ws().await?;
-    println!("Obtained session_id: {}", session_id);
-
-    // Register the websocket subscription using the numeric broadcaster id.
-    register_ws_subscription(token_str, &numeric_broadcaster_id, &session_id)
-        .await?;
-
-    println!("Running WebSocket message loop...");
-    let (_write, mut read) = ws_stream.split();
-
-    while let Some(message) = read.next().await {
-        let message = message?;
-        if message.is_text() {
-            let text = message.to_text()?;
-            //TODO: Create a format message function.
-            //println!("Received message: {}", text);
-            let event: Value = serde_json::from_str(text)?;
-            // Look inside the "payload" object
-            if let Some(payload) = event.get("payload") {
-                if let Some(subscription) = payload.get("subscription") {
-                    if let Some(event_type) =
-                        subscription.get("type").and_then(|v| v.as_str())
-                    {
-                        if event_type == "channel.channel_points_custom_reward_redemption.add" {
-                            let payload = payload.clone();
-                            std::thread::spawn(|| {
-                                crate::redemption::handle_redemption(payload).ok();
+    let (ws_stream, mut session_id, mut keepalive) =
+        connect_eventsub_ws(EVENTSUB_WS_URL).await?;
+    state.set_connected(true);
+    info!(session_id = %session_id, "Obtained session_id.");
+
+    let mut own_subscriptions =
+        subscribe_channels(channels, &session_id).await?;
+    state.set_subscribed(true);
+    if subscription_active() {
+        crate::health::notify_ready();
+    }
+
+    info!(
+        session_id = %session_id,
+        channels = channels.len(),
+        "Running WebSocket message loop..."
+    );
+    let (mut write, mut read) = ws_stream.split();
+
+    loop {
+        let message = tokio::select! {
+            message = read.next() => match message {
+                Some(Ok(message)) => Some(message),
+                Some(Err(e)) => {
+                    warn!(session_id = %session_id, "WebSocket failed: {}", e);
+                    None
+                }
+                None => {
+                    warn!(
+                        session_id = %session_id,
+                        "Twitch closed the connection."
+                    );
+                    None
+                }
+            },
+            // Twitch sends at least a keepalive within the timeout; silence
+            // means the connection is gone.
+            _ = tokio::time::sleep(keepalive + KEEPALIVE_GRACE) => {
+                metrics::KEEPALIVE_TIMEOUTS.inc();
+                warn!(
+                    session_id = %session_id,
+                    "No message within the keepalive timeout."
+                );
+                None
+            },
+            _ = stop.changed() => {
+                unsubscribe_channels(channels, &own_subscriptions).await;
+                state.set_subscribed(false);
+                let close = CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "shutting down".into(),
+                };
+                if let Err(e) = write.send(Message::Close(Some(close))).await {
+                    error!("Failed to close WebSocket cleanly: {}", e);
+                }
+                break;
+            }
+        };
+
+        let lost = match message {
+            Some(message) if message.is_text() => {
+                let text = message.to_text()?;
+                debug!(
+                    session_id = %session_id,
+                    message = %format_message(text),
+                    "Received message"
+                );
+                crate::recording::record(text);
+                // Twitch asks us to move to another server; the
+                // subscriptions move along, so only the connection is
+                // replaced.
+                if let Some(url) = extract_reconnect_url(text) {
+                    info!(
+                        session_id = %session_id,
+                        "Twitch requested a reconnect."
+                    );
+                    match connect_eventsub_ws(&url).await {
+                        Ok((ws_stream, new_session_id, new_keepalive)) => {
+                            (write, read) = ws_stream.split();
+                            session_id = new_session_id;
+                            keepalive = new_keepalive;
+                            metrics::RECONNECTS.inc();
+                            info!(session_id = %session_id, "Reconnected.");
+                            false
+                        }
+                        Err(e) => {
+                            warn!(
+                                session_id = %session_id,
+                                "Failed to follow the reconnect request: {}",
+                                e
+                            );
+                            true
+                        }
+                    }
+                } else {
+                    match serde_json::from_str::<Value>(text) {
+                        Ok(event) => {
+                            dispatch_message(&event, |payload| {
+                                helix_for(channels, payload).clone()
                             });
                         }
+                        Err(e) => warn!("Skipping unreadable message: {}", e),
                     }
+                    false
                 }
             }
+            Some(_) => false,
+            None => true,
+        };
+        if !lost {
+            continue;
+        }
+
+        // Start over with a new session; the old subscriptions stopped
+        // delivering with the connection.
+        state.set_connected(false);
+        state.set_subscribed(false);
+        let reconnected =
+            retry("reconnect to EventSub", reconnect_delay, &mut stop, || {
+                open_session(channels)
+            })
+            .await;
+        unsubscribe_channels(channels, &own_subscriptions).await;
+        let Some((ws_stream, new_session_id, new_keepalive, subscriptions)) =
+            reconnected
+        else {
+            break;
+        };
+        own_subscriptions = subscriptions;
+        state.set_connected(true);
+        state.set_subscribed(true);
+        (write, read) = ws_stream.split();
+        session_id = new_session_id;
+        keepalive = new_keepalive;
+        metrics::RECONNECTS.inc();
+        info!(session_id = %session_id, "Reconnected.");
+    }
+
+    Ok(())
+}
+
+/// Runs a session of `channels` until `stop` fires, starting it over with
+/// backoff whenever it fails, so one failing session leaves the others
+/// alone.
+async fn supervise_session(
+    channels: Vec<ChannelSubscriber>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut failures = 0;
+    loop {
+        let e = match run_session(&channels, stop.clone()).await {
+            Ok(()) => return,
+            Err(e) => e.to_string(),
+        };
+        failures += 1;
+        let wait = reconnect_delay(failures);
+        error!(
+            wait_secs = wait.as_secs_f32(),
+            "EventSub session failed, starting it over: {}", e
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = stop.changed() => return,
         }
     }
+}
 
+/// Connects the channels to the Twitch EventSub WebSocket, spread over as
+/// many sessions as Twitch's limits need, and processes incoming messages
+/// until shutdown. Redemption and chat events are delegated to their
+/// handlers.
+pub async fn run_eventsub_ws_service(
+    channels: Vec<ChannelSubscriber>,
+) -> Result<(), Box<dyn Error>> {
+    // Remove subscriptions left behind in a failed state by earlier runs,
+    // once per user since each user only sees their own.
+    let mut pruned = Vec::new();
+    for channel in &channels {
+        let user_id = channel.helix.user_id();
+        if pruned.contains(&user_id) {
+            continue;
+        }
+        pruned.push(user_id);
+        if let Err(e) = prune_subscriptions(&channel.helix).await {
+            warn!("Could not prune stale subscriptions: {}", e);
+        }
+    }
+
+    let sessions = plan_sessions(
+        channels,
+        |channel| channel.helix.user_id().to_string(),
+        ChannelSubscriber::subscriptions,
+    )?;
+    SESSIONS.store(sessions.len(), Ordering::SeqCst);
+    info!(sessions = sessions.len(), "Starting EventSub sessions.");
+
+    let (stop, stopped) = watch::channel(false);
+    let sessions = futures_util::future::join_all(
+        sessions
+            .into_iter()
+            .map(|channels| supervise_session(channels, stopped.clone())),
+    );
+    tokio::pin!(sessions);
+    tokio::select! {
+        // Sessions only end once stopped.
+        _ = &mut sessions => return Ok(()),
+        _ = shutdown::signal() => {}
+    }
+
+    let mode = DrainMode::from_env();
+    info!("Shutdown requested, no longer accepting events.");
+    crate::health::notify_stopping();
+    COORDINATOR.begin(mode);
+    // Every session deletes its subscriptions and closes its connection.
+    stop.send(true).ok();
+    sessions.await;
+
+    let timeout = shutdown::drain_timeout();
+    info!(
+        timeout_secs = timeout.as_secs(),
+        mode = ?mode,
+        "Waiting for playback to finish..."
+    );
+    tokio::select! {
+        drained = COORDINATOR.drain(timeout) => {
+            if !drained {
+                warn!("Playback did not finish in time.");
+                crate::channels::stop_all();
+            }
+        }
+        _ = shutdown::signal() => {
+            warn!("Second signal received, exiting now.");
+            COORDINATOR.interrupt();
+            // The runtime waits for blocking tasks on exit, and playback
+            // blocks until its clip ends.
+            crate::channels::stop_all();
+        }
+    }
+    crate::channels::flush();
+    crate::recording::flush();
+    COORDINATOR.flush();
     Ok(())
 }
 
@@ -246,12 +1066,174 @@ mod tests {
         "#;
         let session_id = extract_session_id(welcome_msg);
         assert_eq!(session_id, Some("TestSessionID123".to_string()));
+        assert_eq!(
+            extract_keepalive_timeout(welcome_msg),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(extract_reconnect_url(welcome_msg), None);
     }
 
-    #[tokio::test]
-    async fn test_get_numeric_broadcaster_id_invalid() {
-        let result =
-            get_numeric_broadcaster_id("nonexistentuser", "fake_token").await;
+    #[test]
+    fn test_get_numeric_broadcaster_id_invalid() {
+        let token = twitch_oauth2::UserToken::from_existing_unchecked(
+            twitch_oauth2::AccessToken::new("fake_token".to_string()),
+            None,
+            twitch_oauth2::ClientId::new("fake_client".to_string()),
+            None,
+            "me".to_string().into(),
+            "1".to_string().into(),
+            None,
+            None,
+        );
+        let helix = Helix::new(token, None);
+        let result = crate::channels::broadcaster_id("nonexistentuser", &helix);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_extract_reconnect_url() {
+        let reconnect_msg = r#"
+        {
+            "metadata": { "message_type": "session_reconnect" },
+            "payload": {
+                "session": {
+                    "id": "TestSessionID123",
+                    "status": "reconnecting",
+                    "reconnect_url": "wss://eventsub.wss.twitch.tv?id=abc"
+                }
+            }
+        }
+        "#;
+        assert_eq!(
+            extract_reconnect_url(reconnect_msg),
+            Some("wss://eventsub.wss.twitch.tv?id=abc".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_subscription_page() {
+        let body = r#"
+        {
+            "data": [{
+                "id": "f1c2a387-161a-49f9-a165-0f21d7a4e1c4",
+                "status": "enabled",
+                "type": "channel.channel_points_custom_reward_redemption.add",
+                "version": "1",
+                "cost": 0,
+                "condition": { "broadcaster_user_id": "1234" },
+                "transport": { "method": "websocket", "session_id": "abc" },
+                "created_at": "2025-04-01T00:00:00Z"
+            }],
+            "total": 1,
+            "total_cost": 0,
+            "max_total_cost": 10,
+            "pagination": {}
+        }
+        "#;
+        let page: SubscriptionPage = serde_json::from_str(body).unwrap();
+        assert_eq!(page.data.len(), 1);
+        assert_eq!(page.data[0].status, "enabled");
+        assert!(!page.data[0].is_stale());
+        assert_eq!(page.max_total_cost, 10);
+        assert!(page.pagination.cursor.is_none());
+    }
+
+    #[test]
+    fn test_failed_subscriptions_are_stale() {
+        let sub = Subscription {
+            id: "id".to_string(),
+            status: "websocket_disconnected".to_string(),
+            event_type: "channel.channel_points_custom_reward_redemption.add"
+                .to_string(),
+            version: "1".to_string(),
+            created_at: "2025-04-01T00:00:00Z".to_string(),
+            cost: 0,
+        };
+        assert!(sub.is_stale());
+    }
+
+    #[test]
+    fn test_reconnect_delay_backs_off_up_to_a_limit() {
+        assert_eq!(reconnect_delay(1), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(3), Duration::from_secs(4));
+        assert_eq!(reconnect_delay(7), MAX_RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(100), MAX_RECONNECT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_success_or_stop() {
+        let (stop, mut stopped) = watch::channel(false);
+        let mut attempts = 0;
+        let result = retry(
+            "connect",
+            |_| Duration::from_millis(1),
+            &mut stopped,
+            || {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 3 {
+                        Err("DNS blip".into())
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            },
+        )
+        .await;
+        assert_eq!(result, Some(3));
+
+        stop.send(true).unwrap();
+        let result: Option<()> = retry(
+            "connect",
+            |_| Duration::from_secs(60),
+            &mut stopped,
+            || async { Err("offline".into()) },
+        )
+        .await;
+        assert_eq!(result, None);
+    }
+
+    #[tokio::test]
+    async fn test_connects_to_a_reconnect_url() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let welcome = json!({
+                "metadata": { "message_type": "session_welcome" },
+                "payload": { "session": {
+                    "id": "moved",
+                    "keepalive_timeout_seconds": 30
+                } }
+            });
+            ws.send(Message::text(welcome.to_string())).await.unwrap();
+            // Keep the connection open until the client is done.
+            ws.next().await;
+        });
+
+        let (_ws, session_id, keepalive) =
+            connect_eventsub_ws(&url).await.unwrap();
+        assert_eq!(session_id, "moved");
+        assert_eq!(keepalive, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_plans_sessions_within_limits() {
+        let user = |c: &(&str, usize)| c.0.to_string();
+        let cost = |c: &(&str, usize)| c.1;
+        let sessions =
+            plan_sessions(vec![("a", 2), ("b", 1), ("a", 2)], user, cost)
+                .unwrap();
+        assert_eq!(sessions, vec![vec![("a", 2), ("a", 2)], vec![("b", 1)]]);
+
+        // 151 channels of two subscriptions fill one session and start a
+        // second one.
+        let sessions = plan_sessions(vec![("a", 2); 151], user, cost).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].len(), 150);
+        assert!(plan_sessions(vec![("a", 2); 451], user, cost).is_err());
+    }
 }
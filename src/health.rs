@@ -0,0 +1,137 @@
+use crate::auth::token_is_valid;
+use crate::eventsub::{session_connected, subscription_active};
+use crate::sound::Player;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What the bot needs to handle redemptions, as reported by `/readyz`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Readiness {
+    pub token_valid: bool,
+    pub session_connected: bool,
+    pub subscription_active: bool,
+    pub audio_available: bool,
+}
+
+impl Readiness {
+    /// Reads the current state. Probes the audio devices, so it may block.
+    pub fn check(player: &Player) -> Readiness {
+        Readiness {
+            token_valid: token_is_valid(),
+            session_connected: session_connected(),
+            subscription_active: subscription_active(),
+            audio_available: player.audio_available(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.token_valid
+            && self.session_connected
+            && self.subscription_active
+            && self.audio_available
+    }
+}
+
+/// The process is up and serving requests.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// 200 once every check passes, 503 otherwise; the body lists the checks.
+async fn readyz(State(player): State<&'static Player>) -> impl IntoResponse {
+    let readiness =
+        tokio::task::spawn_blocking(move || Readiness::check(player))
+            .await
+            .unwrap_or_default();
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Liveness and readiness probes. They need no token, like the metrics.
+pub fn router(player: &'static Player) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(player)
+}
+
+/// Sends a notification to systemd. Does nothing unless the bot was started
+/// by a unit with `Type=notify`, which sets `NOTIFY_SOCKET`.
+#[cfg(unix)]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        tracing::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd that startup finished, the first time redemptions are
+/// subscribed to.
+pub fn notify_ready() {
+    static NOTIFIED: AtomicBool = AtomicBool::new(false);
+    if NOTIFIED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Ready);
+}
+
+/// Tells systemd that the bot is shutting down.
+pub fn notify_stopping() {
+    #[cfg(unix)]
+    notify(sd_notify::NotifyState::Stopping);
+}
+
+/// Pings the systemd watchdog at half its interval while the runtime is
+/// responsive. Returns right away if `WatchdogSec` is not set for the unit.
+pub async fn run_watchdog() {
+    #[cfg(unix)]
+    {
+        let mut usec = 0;
+        if !sd_notify::watchdog_enabled(false, &mut usec) {
+            return;
+        }
+        let interval = std::time::Duration::from_micros(usec) / 2;
+        tracing::info!(
+            interval_ms = interval.as_millis() as u64,
+            "Pinging the systemd watchdog."
+        );
+        loop {
+            notify(sd_notify::NotifyState::Watchdog);
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_only_when_every_check_passes() {
+        let ready = Readiness {
+            token_valid: true,
+            session_connected: true,
+            subscription_active: true,
+            audio_available: true,
+        };
+        assert!(ready.is_ready());
+        let no_session = Readiness {
+            session_connected: false,
+            ..ready
+        };
+        assert!(!no_session.is_ready());
+        assert_eq!(
+            serde_json::to_value(no_session).unwrap()["session_connected"],
+            false
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use crate::auth::{store_refreshed_token, StoredToken};
+use crate::metrics;
+use once_cell::sync::Lazy;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use twitch_oauth2::{AccessToken, ClientId, TwitchToken, UserToken};
+
+/// Base URL of the Helix API.
+const HELIX_URL: &str = "https://api.twitch.tv/helix/";
+
+/// Tries per request, counting the first one.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Wait before the first retry; doubled for every further one.
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How long before it expires a token is refreshed.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Wait before trying again after a failed refresh.
+const REFRESH_RETRY: Duration = Duration::from_secs(60);
+
+/// Connection pool shared by every Helix request and token refresh. OAuth
+/// requests must not follow redirects.
+static HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("failed to build the HTTP client")
+});
+
+/// The shared HTTP client, also used for the OAuth flows.
+pub fn http() -> &'static reqwest::Client {
+    &HTTP
+}
+
+/// Why a Helix request failed.
+#[derive(Debug)]
+pub enum HelixError {
+    /// The request could not be sent or its response not read.
+    Http(reqwest::Error),
+    /// Twitch answered with an error status, after any retries.
+    Status { status: StatusCode, message: String },
+    /// Twitch rejected the token and refreshing it failed.
+    Refresh(String),
+    /// The response was not in the expected format.
+    Decode(serde_json::Error),
+    /// The client is replaying recorded events and sends nothing.
+    Offline,
+}
+
+impl HelixError {
+    /// The HTTP status Twitch answered with, if it answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            HelixError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HelixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HelixError::Http(e) => write!(f, "Helix request failed: {}", e),
+            HelixError::Status { status, message } => {
+                write!(f, "Helix answered {}: {}", status, message)
+            }
+            HelixError::Refresh(e) => {
+                write!(f, "Failed to refresh the access token: {}", e)
+            }
+            HelixError::Decode(e) => {
+                write!(f, "Unexpected Helix response: {}", e)
+            }
+            HelixError::Offline => {
+                write!(f, "Helix requests are disabled while replaying")
+            }
+        }
+    }
+}
+
+impl Error for HelixError {}
+
+/// The rate limit bucket of a token, from the `Ratelimit-*` headers of the
+/// last response.
+#[derive(Default, Debug)]
+struct RateLimit {
+    remaining: Option<u64>,
+    /// Unix time in seconds when the bucket is full again.
+    reset: Option<u64>,
+}
+
+impl RateLimit {
+    fn update(&mut self, headers: &HeaderMap) {
+        let number = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        };
+        if let Some(remaining) = number("ratelimit-remaining") {
+            self.remaining = Some(remaining);
+            self.reset = number("ratelimit-reset");
+        }
+    }
+
+    /// How long to wait before the next request at Unix time `now`: until
+    /// the reset if no points are left.
+    fn wait(&self, now: u64) -> Option<Duration> {
+        if self.remaining != Some(0) {
+            return None;
+        }
+        self.reset
+            .filter(|reset| *reset > now)
+            .map(|reset| Duration::from_secs(reset - now))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// How long to wait before refreshing a token that expires in `expires_in`.
+fn refresh_wait(expires_in: Duration) -> Duration {
+    expires_in.saturating_sub(REFRESH_MARGIN)
+}
+
+/// Wait before retry number `attempt` (1 for the first retry).
+fn backoff(attempt: u32) -> Duration {
+    BASE_BACKOFF * 2u32.pow(attempt.saturating_sub(1))
+}
+
+/// A Helix client acting as one user. Clones share the token, its rate
+/// limit and the connection pool.
+#[derive(Clone)]
+pub struct Helix {
+    token: Arc<RwLock<UserToken>>,
+    /// Where the token is stored, to keep it current after a refresh.
+    token_path: Option<PathBuf>,
+    rate_limit: Arc<Mutex<RateLimit>>,
+    client_id: String,
+    user_id: String,
+    login: String,
+    /// Set for replays, which must not reach Twitch.
+    offline: bool,
+}
+
+impl Helix {
+    pub fn new(token: UserToken, token_path: Option<PathBuf>) -> Helix {
+        Helix {
+            client_id: token.client_id().to_string(),
+            user_id: token.user_id.to_string(),
+            login: token.login.to_string(),
+            token: Arc::new(RwLock::new(token)),
+            token_path,
+            rate_limit: Arc::default(),
+            offline: false,
+        }
+    }
+
+    /// A client that fails every request with [`HelixError::Offline`], for
+    /// replaying recorded events without network access.
+    pub fn offline() -> Helix {
+        let token = UserToken::from_existing_unchecked(
+            AccessToken::new(String::new()),
+            None,
+            ClientId::new(String::new()),
+            None,
+            String::new().into(),
+            String::new().into(),
+            None,
+            None,
+        );
+        Helix {
+            offline: true,
+            ..Helix::new(token, None)
+        }
+    }
+
+    /// Id of the user the client acts as.
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Whether the client is replaying and sends nothing.
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Login of the user the client acts as.
+    pub fn login(&self) -> &str {
+        &self.login
+    }
+
+    /// Sends a request to a Helix endpoint, like `users`, and decodes the
+    /// JSON response.
+    pub async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        endpoint: &str,
+        query: &[(&str, &str)],
+        body: Option<&Value>,
+    ) -> Result<T, HelixError> {
+        let response = self.send(method, endpoint, query, body).await?;
+        let text = response.text().await.map_err(HelixError::Http)?;
+        serde_json::from_str(&text).map_err(HelixError::Decode)
+    }
+
+    /// Like [`Helix::request`], for endpoints that answer without a body.
+    pub async fn execute(
+        &self,
+        method: Method,
+        endpoint: &str,
+        query: &[(&str, &str)],
+        body: Option<&Value>,
+    ) -> Result<(), HelixError> {
+        self.send(method, endpoint, query, body).await.map(|_| ())
+    }
+
+    /// Sends a request, waiting out the rate limit first. Rate limited
+    /// requests are retried, and so are failures of idempotent ones, up to
+    /// `MAX_ATTEMPTS` times with exponential backoff. A rejected token is
+    /// refreshed once.
+    async fn send(
+        &self,
+        method: Method,
+        endpoint: &str,
+        query: &[(&str, &str)],
+        body: Option<&Value>,
+    ) -> Result<Response, HelixError> {
+        if self.offline {
+            return Err(HelixError::Offline);
+        }
+        // Sending a POST twice may create two subscriptions or messages.
+        let idempotent = method != Method::POST;
+        let mut refreshed = false;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let wait = self.rate_limit.lock().unwrap().wait(unix_now());
+            if let Some(wait) = wait {
+                warn!(
+                    wait_secs = wait.as_secs(),
+                    "Helix rate limit reached, waiting."
+                );
+                tokio::time::sleep(wait).await;
+            }
+
+            let access_token =
+                self.token.read().await.token().secret().to_string();
+            let mut request = HTTP
+                .request(method.clone(), format!("{}{}", HELIX_URL, endpoint))
+                .query(query)
+                .header("Client-ID", &self.client_id)
+                .bearer_auth(&access_token);
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(e) if idempotent && attempt < MAX_ATTEMPTS => {
+                    warn!(endpoint, "Helix request failed, retrying: {}", e);
+                    tokio::time::sleep(backoff(attempt)).await;
+                    continue;
+                }
+                Err(e) => return Err(HelixError::Http(e)),
+            };
+            self.rate_limit.lock().unwrap().update(response.headers());
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            metrics::HELIX_ERRORS.inc_with(status.as_str());
+
+            if status == StatusCode::UNAUTHORIZED && !refreshed {
+                refreshed = true;
+                self.refresh(&access_token).await?;
+                continue;
+            }
+            let retry = status == StatusCode::TOO_MANY_REQUESTS
+                || (status.is_server_error() && idempotent);
+            if retry && attempt < MAX_ATTEMPTS {
+                warn!(
+                    endpoint,
+                    status = status.as_u16(),
+                    "Helix request failed, retrying."
+                );
+                // A rate limited request waits for the reset instead, if
+                // Twitch said when that is.
+                if status != StatusCode::TOO_MANY_REQUESTS
+                    || self
+                        .rate_limit
+                        .lock()
+                        .unwrap()
+                        .wait(unix_now())
+                        .is_none()
+                {
+                    tokio::time::sleep(backoff(attempt)).await;
+                }
+                continue;
+            }
+            let message = response.text().await.unwrap_or_default();
+            return Err(HelixError::Status { status, message });
+        }
+    }
+
+    /// Refreshes the token shortly before it expires, for as long as the
+    /// process runs, so it stays valid between requests too.
+    pub async fn keep_token_fresh(self) {
+        loop {
+            let (expires_in, access_token) = {
+                let token = self.token.read().await;
+                (token.expires_in(), token.token().secret().to_string())
+            };
+            tokio::time::sleep(refresh_wait(expires_in)).await;
+            if let Err(e) = self.refresh(&access_token).await {
+                warn!(user = %self.login, "{}", e);
+                tokio::time::sleep(REFRESH_RETRY).await;
+            }
+        }
+    }
+
+    /// Refreshes the token `rejected`, unless another request or the
+    /// background refresh already did, and stores the new one.
+    async fn refresh(&self, rejected: &str) -> Result<(), HelixError> {
+        let mut token = self.token.write().await;
+        if token.token().secret() != rejected {
+            return Ok(());
+        }
+        // A failed refresh consumes the refresh token, so work on a copy.
+        let mut refreshed = token.clone();
+        refreshed
+            .refresh_token(&*HTTP)
+            .await
+            .map_err(|e| HelixError::Refresh(e.to_string()))?;
+        if let Some(path) = &self.token_path {
+            store_refreshed_token(path, &refreshed);
+        }
+        *token = refreshed;
+        info!(user = %self.login, "Refreshed the access token.");
+        Ok(())
+    }
+}
+
+/// Logs in for a channel like [`StoredToken::ensure_twitch_token`] and
+/// returns a client acting with its token. `None` is the `BROADCASTER_ID`
+/// channel.
+pub async fn connect(channel: Option<&str>) -> Result<Helix, Box<dyn Error>> {
+    let token = StoredToken::ensure_twitch_token(channel).await?;
+    Ok(Helix::new(token, Some(StoredToken::token_path(channel)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_waits_for_the_rate_limit_reset() {
+        let mut limit = RateLimit::default();
+        assert_eq!(limit.wait(100), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Ratelimit-Remaining", HeaderValue::from_static("0"));
+        headers.insert("Ratelimit-Reset", HeaderValue::from_static("103"));
+        limit.update(&headers);
+        assert_eq!(limit.wait(100), Some(Duration::from_secs(3)));
+        assert_eq!(limit.wait(103), None);
+
+        headers.insert("Ratelimit-Remaining", HeaderValue::from_static("799"));
+        limit.update(&headers);
+        assert_eq!(limit.wait(100), None);
+
+        assert_eq!(backoff(1), Duration::from_millis(500));
+        assert_eq!(backoff(3), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_refreshes_ahead_of_expiry() {
+        let hours = Duration::from_secs(4 * 60 * 60);
+        assert_eq!(refresh_wait(hours), hours - REFRESH_MARGIN);
+        assert_eq!(refresh_wait(Duration::from_secs(60)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_reports_status_errors() {
+        let error = HelixError::Status {
+            status: StatusCode::FORBIDDEN,
+            message: "missing scope".to_string(),
+        };
+        assert_eq!(error.status(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(
+            error.to_string(),
+            "Helix answered 403 Forbidden: missing scope"
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use crate::config::data_path;
+use crate::sound::Outcome;
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection};
+use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// What became of a redemption, as stored in the history.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistoryOutcome {
+    /// A sound or speech played.
+    Played,
+    /// Nothing played: alerts were paused or the reward has nothing set up.
+    Skipped,
+    /// The viewer got their points back.
+    Refunded,
+    /// Moderation blocked the redemption.
+    Blocked,
+    /// The sound could not be played.
+    Failed,
+}
+
+impl HistoryOutcome {
+    /// Summarizes how a redemption was handled and whether it was refunded.
+    /// Blocked redemptions stay blocked even if refunded.
+    pub fn new(outcome: &Outcome, refunded: bool) -> HistoryOutcome {
+        match outcome {
+            Outcome::Blocked => HistoryOutcome::Blocked,
+            _ if refunded => HistoryOutcome::Refunded,
+            Outcome::Handled { .. } => HistoryOutcome::Played,
+            Outcome::Failed => HistoryOutcome::Failed,
+            Outcome::NoMatch | Outcome::Paused | Outcome::Skipped => {
+                HistoryOutcome::Skipped
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HistoryOutcome::Played => "played",
+            HistoryOutcome::Skipped => "skipped",
+            HistoryOutcome::Refunded => "refunded",
+            HistoryOutcome::Blocked => "blocked",
+            HistoryOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// A redemption stored in the history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Unix time in seconds.
+    pub at: u64,
+    pub user_id: String,
+    pub user_login: String,
+    pub reward_id: String,
+    pub reward_title: String,
+    pub input: String,
+    /// The sound that played, if one did.
+    pub sound: Option<String>,
+    /// One of `played`, `skipped`, `refunded`, `blocked` or `failed`.
+    pub outcome: String,
+}
+
+/// Current Unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Redemption history in an SQLite database.
+pub struct History {
+    conn: Mutex<Connection>,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it and its table if needed.
+    pub fn open(path: &Path) -> rusqlite::Result<History> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS redemptions (
+                id INTEGER PRIMARY KEY,
+                at INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                user_login TEXT NOT NULL,
+                reward_id TEXT NOT NULL,
+                reward_title TEXT NOT NULL,
+                input TEXT NOT NULL,
+                sound TEXT,
+                outcome TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS redemptions_at ON redemptions (at);
+            CREATE INDEX IF NOT EXISTS redemptions_user
+                ON redemptions (user_login);",
+        )?;
+        Ok(History {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn record(&self, entry: &Entry) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO redemptions
+                (at, user_id, user_login, reward_id, reward_title, input,
+                 sound, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.at,
+                entry.user_id,
+                entry.user_login,
+                entry.reward_id,
+                entry.reward_title,
+                entry.input,
+                entry.sound,
+                entry.outcome,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The most played sounds since `since` (Unix seconds), with how often
+    /// each played.
+    pub fn top_sounds(
+        &self,
+        since: u64,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<(String, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT sound, COUNT(*) AS n FROM redemptions
+             WHERE outcome = 'played' AND sound IS NOT NULL AND at >= ?1
+             GROUP BY sound ORDER BY n DESC, sound LIMIT ?2",
+        )?;
+        let rows = statement
+            .query_map(params![since, limit], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect();
+        rows
+    }
+
+    /// The viewers with the most redemptions since `since` (Unix seconds).
+    pub fn top_redeemers(
+        &self,
+        since: u64,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<(String, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT user_login, COUNT(*) AS n FROM redemptions
+             WHERE at >= ?1
+             GROUP BY user_login ORDER BY n DESC, user_login LIMIT ?2",
+        )?;
+        let rows = statement
+            .query_map(params![since, limit], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect();
+        rows
+    }
+
+    /// The latest redemptions of a viewer, by login ignoring case or by
+    /// user id, newest first.
+    pub fn for_user(
+        &self,
+        user: &str,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Entry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT at, user_id, user_login, reward_id, reward_title, input,
+                    sound, outcome
+             FROM redemptions
+             WHERE user_login = ?1 COLLATE NOCASE OR user_id = ?1
+             ORDER BY at DESC, id DESC LIMIT ?2",
+        )?;
+        let rows = statement
+            .query_map(params![user, limit], |row| {
+                Ok(Entry {
+                    at: row.get(0)?,
+                    user_id: row.get(1)?,
+                    user_login: row.get(2)?,
+                    reward_id: row.get(3)?,
+                    reward_title: row.get(4)?,
+                    input: row.get(5)?,
+                    sound: row.get(6)?,
+                    outcome: row.get(7)?,
+                })
+            })?
+            .collect();
+        rows
+    }
+
+    /// Deletes the entries older than `before` (Unix seconds). Returns how
+    /// many were deleted.
+    pub fn prune(&self, before: u64) -> rusqlite::Result<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM redemptions WHERE at < ?1", params![before])
+    }
+}
+
+/// Path of the database: `HISTORY_DB`, or `history.db` next to the config.
+pub fn history_path() -> Result<PathBuf, Box<dyn Error>> {
+    match env::var("HISTORY_DB") {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => data_path("history.db"),
+    }
+}
+
+/// How long entries are kept, from `HISTORY_RETENTION_DAYS`. Unset or `0`
+/// keeps them forever.
+pub fn retention() -> Option<Duration> {
+    env::var("HISTORY_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|days| *days > 0)
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+}
+
+/// Opens the history database from the environment.
+pub fn open_from_env() -> Result<History, Box<dyn Error>> {
+    Ok(History::open(&history_path()?)?)
+}
+
+/// History of the running bot; `None` if the database could not be opened.
+static HISTORY: Lazy<Option<History>> = Lazy::new(|| match open_from_env() {
+    Ok(history) => Some(history),
+    Err(e) => {
+        warn!("Redemption history disabled, failed to open it: {}", e);
+        None
+    }
+});
+
+/// Cleared by [`disable`].
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Stops storing redemptions, so replayed ones stay out of the history.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Stores a redemption, warning if that fails.
+pub fn record(entry: &Entry) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(history) = HISTORY.as_ref() {
+        if let Err(e) = history.record(entry) {
+            warn!("Failed to store redemption in the history: {}", e);
+        }
+    }
+}
+
+/// Deletes entries past the retention period now and then every hour.
+/// Returns right away if no retention is set.
+pub async fn enforce_retention() {
+    let Some(retention) = retention() else {
+        return;
+    };
+    loop {
+        let before = now().saturating_sub(retention.as_secs());
+        let pruned = tokio::task::spawn_blocking(move || {
+            HISTORY.as_ref().map(|history| history.prune(before))
+        })
+        .await;
+        match pruned {
+            Ok(Some(Ok(n))) if n > 0 => {
+                info!(count = n, "Pruned old redemption history.")
+            }
+            Ok(Some(Err(e))) => warn!("Failed to prune history: {}", e),
+            _ => {}
+        }
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        at: u64,
+        login: &str,
+        sound: Option<&str>,
+        outcome: &str,
+    ) -> Entry {
+        Entry {
+            at,
+            user_id: format!("id-{}", login),
+            user_login: login.to_string(),
+            reward_id: "r1".to_string(),
+            reward_title: "Any Sound".to_string(),
+            input: String::new(),
+            sound: sound.map(str::to_string),
+            outcome: outcome.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_queries_and_prunes_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(&dir.path().join("history.db")).unwrap();
+        history
+            .record(&entry(100, "alice", Some("Honk"), "played"))
+            .unwrap();
+        history
+            .record(&entry(200, "alice", Some("Boom"), "played"))
+            .unwrap();
+        history
+            .record(&entry(300, "bob", Some("Boom"), "played"))
+            .unwrap();
+        history
+            .record(&entry(400, "bob", Some("Honk"), "refunded"))
+            .unwrap();
+        history
+            .record(&entry(500, "alice", None, "blocked"))
+            .unwrap();
+
+        assert_eq!(
+            history.top_sounds(0, 10).unwrap(),
+            vec![("Boom".to_string(), 2), ("Honk".to_string(), 1)]
+        );
+        assert_eq!(
+            history.top_sounds(250, 10).unwrap(),
+            vec![("Boom".to_string(), 1)]
+        );
+        assert_eq!(
+            history.top_redeemers(0, 1).unwrap(),
+            vec![("alice".to_string(), 3)]
+        );
+        let alice = history.for_user("ALICE", 2).unwrap();
+        assert_eq!(alice[0], entry(500, "alice", None, "blocked"));
+        assert_eq!(alice[1].at, 200);
+        assert_eq!(history.for_user("id-bob", 10).unwrap().len(), 2);
+
+        assert_eq!(history.prune(300).unwrap(), 2);
+        assert_eq!(
+            history.top_redeemers(0, 10).unwrap(),
+            vec![("bob".to_string(), 2), ("alice".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_summarizes_outcomes() {
+        assert_eq!(
+            HistoryOutcome::new(&Outcome::Handled { clip: None }, false),
+            HistoryOutcome::Played
+        );
+        assert_eq!(
+            HistoryOutcome::new(&Outcome::NoMatch, true),
+            HistoryOutcome::Refunded
+        );
+        assert_eq!(
+            HistoryOutcome::new(&Outcome::Paused, false),
+            HistoryOutcome::Skipped
+        );
+        assert_eq!(
+            HistoryOutcome::new(&Outcome::Blocked, true),
+            HistoryOutcome::Blocked
+        );
+        assert_eq!(
+            HistoryOutcome::new(&Outcome::Failed, false),
+            HistoryOutcome::Failed
+        );
+    }
+}
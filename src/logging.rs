@@ -0,0 +1,193 @@
+use clap::ValueEnum;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::env;
+use std::io::{self, Write};
+use std::sync::RwLock;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Placeholder written instead of a secret.
+const REDACTED: &str = "[REDACTED]";
+
+/// JSON keys whose values are never printed by `format_message`.
+const SECRET_KEYS: [&str; 5] = [
+    "access_token",
+    "refresh_token",
+    "client_secret",
+    "secret",
+    "token",
+];
+
+/// Output format of the log lines.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable, one line per event.
+    Pretty,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Secrets that must be masked wherever they would appear in the output.
+static SECRETS: Lazy<RwLock<Vec<String>>> =
+    Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Registers a secret so it is replaced by `[REDACTED]` in every log line.
+pub fn register_secret(secret: &str) {
+    // Very short values would mask ordinary words, so they are not tracked.
+    if secret.len() < 6 {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+/// Registers the secrets found in the loaded configuration.
+pub fn register_config_secrets() {
+    for key in ["CLIENT_SECRET", "EVENTSUB_SECRET", "CONTROL_API_TOKEN"] {
+        if let Ok(value) = env::var(key) {
+            register_secret(&value);
+        }
+    }
+}
+
+/// Replaces every registered secret in `text`.
+pub fn redact(text: &str) -> String {
+    let secrets = SECRETS.read().unwrap();
+    let mut out = text.to_string();
+    for secret in secrets.iter() {
+        if out.contains(secret.as_str()) {
+            out = out.replace(secret.as_str(), REDACTED);
+        }
+    }
+    out
+}
+
+/// Writer that redacts registered secrets before writing to stdout.
+pub struct RedactingWriter;
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        io::stdout().write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RedactingWriter {
+    type Writer = RedactingWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter
+    }
+}
+
+/// Installs the global logger. The level and format come from the command
+/// line, then `LOG_LEVEL`/`LOG_FORMAT`, then default to `info` and pretty.
+/// `RUST_LOG` takes precedence over all of them for fine-grained filters.
+pub fn init(level: Option<String>, format: Option<LogFormat>) {
+    let level = level
+        .or_else(|| env::var("LOG_LEVEL").ok())
+        .unwrap_or_else(|| "info".to_string());
+    let format = format.or_else(|| {
+        env::var("LOG_FORMAT")
+            .ok()
+            .and_then(|f| LogFormat::from_str(&f, true).ok())
+    });
+
+    // Dependencies are only shown when they warn, our own crate at `level`.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!("warn,twitch_soundbot={}", level))
+    });
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(RedactingWriter);
+    let result = match format.unwrap_or(LogFormat::Pretty) {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to initialise logging: {}", e);
+    }
+}
+
+/// Replaces the values of secret-looking keys in a JSON value.
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// Formats a raw EventSub message for debug output: the message type and
+/// subscription type up front, followed by the compact payload with secrets
+/// removed. Text that is not JSON is returned redacted but otherwise as is.
+pub fn format_message(text: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(text) else {
+        return redact(text);
+    };
+    redact_value(&mut value);
+
+    let message_type = value
+        .pointer("/metadata/message_type")
+        .and_then(|v| v.as_str())
+        .unwrap_or("unknown")
+        .to_string();
+    let subscription_type = value
+        .pointer("/metadata/subscription_type")
+        .and_then(|v| v.as_str())
+        .map(|t| format!(" ({})", t))
+        .unwrap_or_default();
+    let payload = value.get("payload").cloned().unwrap_or(Value::Null);
+
+    redact(&format!(
+        "{}{}: {}",
+        message_type, subscription_type, payload
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacts_registered_secrets() {
+        register_secret("supersecretvalue");
+        assert_eq!(
+            redact("token=supersecretvalue!"),
+            "token=[REDACTED]!".to_string()
+        );
+    }
+
+    #[test]
+    fn test_format_message_hides_secret_keys() {
+        let text = r#"
+        {
+            "metadata": {
+                "message_type": "notification",
+                "subscription_type": "channel.follow"
+            },
+            "payload": { "transport": { "secret": "s3cr3t-value" } }
+        }
+        "#;
+        let formatted = format_message(text);
+        assert!(formatted.starts_with("notification (channel.follow): "));
+        assert!(!formatted.contains("s3cr3t-value"));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::audio::Clip;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::warn;
+
+/// Level measurements of a clip.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Loudness {
+    /// Root mean square of all samples, linear (1.0 = full scale).
+    pub rms: f32,
+    /// Largest absolute sample value, linear.
+    pub peak: f32,
+}
+
+impl Loudness {
+    /// Measures the RMS and peak level of a decoded clip.
+    pub fn analyze(clip: &Clip) -> Loudness {
+        let mut sum = 0.0f64;
+        let mut peak = 0.0f32;
+        for sample in clip.samples.iter() {
+            sum += (*sample as f64) * (*sample as f64);
+            peak = peak.max(sample.abs());
+        }
+        let count = clip.samples.len().max(1) as f64;
+        Loudness {
+            rms: (sum / count).sqrt() as f32,
+            peak,
+        }
+    }
+
+    /// Gain that brings the clip's RMS level to `target_dbfs`, reduced where
+    /// needed so the peak does not clip. Silent clips are left alone.
+    pub fn normalization_gain(&self, target_dbfs: f32) -> f32 {
+        if self.rms <= f32::EPSILON || self.peak <= f32::EPSILON {
+            return 1.0;
+        }
+        let target = 10f32.powf(target_dbfs / 20.0);
+        (target / self.rms).min(1.0 / self.peak)
+    }
+}
+
+/// Cache entry, valid while the file keeps its size and modification time.
+#[derive(Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    len: u64,
+    modified: u64,
+    loudness: Loudness,
+}
+
+/// Loudness measurements persisted per file, so each clip is only analysed
+/// once until it changes on disk.
+pub struct LoudnessCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+    /// Whether entries were added since the file was last written.
+    dirty: bool,
+}
+
+/// Size and modification time identifying one version of a file.
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    let modified = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((meta.len(), modified))
+}
+
+impl LoudnessCache {
+    /// Loads the cache stored at `path`, starting empty if it is missing or
+    /// unreadable.
+    pub fn load(path: PathBuf) -> LoudnessCache {
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        LoudnessCache {
+            path,
+            entries,
+            dirty: false,
+        }
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)?;
+        Ok(())
+    }
+
+    /// Writes the entries added since the last successful save, if any.
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match self.save() {
+            Ok(()) => self.dirty = false,
+            Err(e) => {
+                warn!(path = ?self.path, "Failed to save loudness cache: {}", e)
+            }
+        }
+    }
+
+    /// Returns the loudness of `file`, analysing `clip` and updating the
+    /// cache file if there is no up-to-date entry.
+    pub fn get_or_analyze(&mut self, file: &Path, clip: &Clip) -> Loudness {
+        let key = file.to_string_lossy().to_string();
+        let stamp = file_stamp(file);
+        if let (Some(entry), Some((len, modified))) =
+            (self.entries.get(&key), stamp)
+        {
+            if entry.len == len && entry.modified == modified {
+                return entry.loudness;
+            }
+        }
+
+        let loudness = Loudness::analyze(clip);
+        if let Some((len, modified)) = stamp {
+            self.entries.insert(
+                key,
+                CacheEntry {
+                    len,
+                    modified,
+                    loudness,
+                },
+            );
+            self.dirty = true;
+            self.flush();
+        }
+        loudness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn clip(samples: Vec<f32>) -> Clip {
+        Clip {
+            channels: 1,
+            sample_rate: 8000,
+            samples: Arc::new(samples),
+        }
+    }
+
+    #[test]
+    fn test_gain_reaches_target_without_clipping() {
+        let loud = Loudness::analyze(&clip(vec![0.5, -0.5, 0.5, -0.5]));
+        assert_eq!(
+            loud,
+            Loudness {
+                rms: 0.5,
+                peak: 0.5
+            }
+        );
+
+        // -20 dBFS is 0.1 linear, so a 0.5 RMS clip is turned down 5x.
+        assert!((loud.normalization_gain(-20.0) - 0.2).abs() < 1e-6);
+        // Reaching 0 dBFS RMS would clip the peak, so gain stops at 2x.
+        assert!((loud.normalization_gain(0.0) - 2.0).abs() < 1e-6);
+        assert_eq!(
+            Loudness::analyze(&clip(vec![0.0; 4])).normalization_gain(-20.0),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_cache_is_persisted_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("sound.wav");
+        fs::write(&file, b"not really audio").unwrap();
+        let cache_path = dir.path().join("loudness.json");
+
+        let mut cache = LoudnessCache::load(cache_path.clone());
+        let first = cache.get_or_analyze(&file, &clip(vec![0.25, -0.25]));
+
+        // A fresh cache reads the stored entry instead of the new samples.
+        let mut reloaded = LoudnessCache::load(cache_path);
+        let second = reloaded.get_or_analyze(&file, &clip(vec![1.0, -1.0]));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_flush_retries_a_failed_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("sound.wav");
+        fs::write(&file, b"not really audio").unwrap();
+        let cache_path = dir.path().join("missing").join("loudness.json");
+
+        let mut cache = LoudnessCache::load(cache_path.clone());
+        cache.get_or_analyze(&file, &clip(vec![0.25, -0.25]));
+        assert!(!cache_path.exists());
+
+        fs::create_dir(dir.path().join("missing")).unwrap();
+        cache.flush();
+        assert!(cache_path.exists());
+    }
+}
@@ -1,24 +1,45 @@
+mod api;
+mod audio;
 mod auth;
+mod cache;
+mod channels;
+mod chat;
+mod cli;
 mod config;
 mod eventsub;
+mod health;
+mod helix;
+mod history;
+mod logging;
+mod loudness;
+mod metrics;
+mod overlay;
+mod pool;
+mod recording;
 mod redemption;
+mod shutdown;
 mod sound;
+mod tts;
 
-use auth::StoredToken;
-use config::ensure_config;
-use eventsub::run_eventsub_ws_service;
-use tokio;
+use clap::Parser;
+use cli::Cli;
+use shutdown::COORDINATOR;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load configuration (interactive if missing)
-    ensure_config()?;
+async fn main() -> ExitCode {
+    // Parse the command line; with no subcommand the bot simply runs.
+    let cli = Cli::parse();
 
-    // Obtain a Twitch token (using your existing user token flow)
-    let user_token = StoredToken::ensure_twitch_token().await?;
+    // Pick up LOG_* settings from the config file before logging starts.
+    config::load_existing_config();
+    logging::init(cli.log_level.clone(), cli.log_format);
 
-    // Run the EventSub WebSocket service using the obtained token.
-    run_eventsub_ws_service(&user_token).await?;
-
-    Ok(())
+    match cli::run(cli).await {
+        Ok(()) => COORDINATOR.exit_code(),
+        Err(e) => {
+            tracing::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
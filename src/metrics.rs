@@ -0,0 +1,236 @@
+use crate::shutdown::COORDINATOR;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A Prometheus counter, optionally split by one label.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    label: Option<&'static str>,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Counter {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        label: Option<&'static str>,
+    ) -> Counter {
+        Counter {
+            name,
+            help,
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Counts one occurrence of a counter without a label.
+    pub fn inc(&self) {
+        self.inc_with("");
+    }
+
+    /// Counts one occurrence with the given label value.
+    pub fn inc_with(&self, value: &str) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(value.to_string())
+            .or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).ok();
+        writeln!(out, "# TYPE {} counter", self.name).ok();
+        let values = self.values.lock().unwrap();
+        match self.label {
+            None => {
+                let total = values.values().sum::<u64>();
+                writeln!(out, "{} {}", self.name, total).ok();
+            }
+            Some(label) => {
+                for (value, count) in values.iter() {
+                    writeln!(
+                        out,
+                        "{}{{{}=\"{}\"}} {}",
+                        self.name,
+                        label,
+                        escape(value),
+                        count
+                    )
+                    .ok();
+                }
+            }
+        }
+    }
+}
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+struct HistogramData {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// A Prometheus histogram of durations in seconds.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str, help: &'static str) -> Histogram {
+        Histogram {
+            name,
+            help,
+            data: Mutex::new(HistogramData {
+                buckets: [0; BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut data = self.data.lock().unwrap();
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            data.buckets[i] += 1;
+        }
+        data.sum += seconds;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).ok();
+        writeln!(out, "# TYPE {} histogram", self.name).ok();
+        let data = self.data.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(data.buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, cumulative
+            )
+            .ok();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, data.count)
+            .ok();
+        writeln!(out, "{}_sum {}", self.name, data.sum).ok();
+        writeln!(out, "{}_count {}", self.name, data.count).ok();
+    }
+}
+
+/// Escapes a label value for the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} gauge", name).ok();
+    writeln!(out, "{} {}", name, value).ok();
+}
+
+pub static NOTIFICATIONS: Counter = Counter::new(
+    "soundbot_eventsub_notifications_total",
+    "EventSub notifications received, by subscription type.",
+    Some("type"),
+);
+
+pub static REDEMPTIONS: Counter = Counter::new(
+    "soundbot_redemptions_total",
+    "Redemptions by outcome (handled, no_match, paused, blocked, skipped).",
+    Some("outcome"),
+);
+
+pub static PLAYBACK_LATENCY: Histogram = Histogram::new(
+    "soundbot_playback_latency_seconds",
+    "Time from a redemption on Twitch to its sound starting.",
+);
+
+pub static RECONNECTS: Counter = Counter::new(
+    "soundbot_eventsub_reconnects_total",
+    "EventSub WebSocket reconnects.",
+    None,
+);
+
+pub static KEEPALIVE_TIMEOUTS: Counter = Counter::new(
+    "soundbot_eventsub_keepalive_timeouts_total",
+    "Times the EventSub WebSocket went silent past its keepalive timeout.",
+    None,
+);
+
+pub static TOKEN_REFRESHES: Counter = Counter::new(
+    "soundbot_token_refreshes_total",
+    "Access token refreshes.",
+    None,
+);
+
+pub static HELIX_ERRORS: Counter = Counter::new(
+    "soundbot_helix_errors_total",
+    "Failed Helix API requests, by HTTP status.",
+    Some("status"),
+);
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    NOTIFICATIONS.render(&mut out);
+    REDEMPTIONS.render(&mut out);
+    PLAYBACK_LATENCY.render(&mut out);
+    render_gauge(
+        &mut out,
+        "soundbot_queue_depth",
+        "Events accepted but not yet handled.",
+        COORDINATOR.in_flight(),
+    );
+    render_gauge(
+        &mut out,
+        "soundbot_playing_sounds",
+        "Sounds playing right now.",
+        COORDINATOR.playing(),
+    );
+    RECONNECTS.render(&mut out);
+    KEEPALIVE_TIMEOUTS.render(&mut out);
+    TOKEN_REFRESHES.render(&mut out);
+    HELIX_ERRORS.render(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renders_counters_and_histograms() {
+        let counter = Counter::new("test_total", "Test.", Some("type"));
+        counter.inc_with("a\"b");
+        counter.inc_with("a\"b");
+        counter.inc_with("c");
+        let histogram = Histogram::new("test_seconds", "Test.");
+        histogram.observe(Duration::from_millis(80));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        histogram.render(&mut out);
+        assert!(out.contains("# TYPE test_total counter\n"));
+        assert!(out.contains("test_total{type=\"a\\\"b\"} 2\n"));
+        assert!(out.contains("test_total{type=\"c\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.05\"} 0\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"30\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("test_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::sound::Player;
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures_util::Stream;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::env;
+use std::path::PathBuf;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Whether a clip started or ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Start,
+    End,
+}
+
+/// A clip starting or ending, pushed to the overlay.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OverlayEvent {
+    /// Identifies the clip, the same in its start and end events.
+    pub id: u64,
+    pub phase: Phase,
+    pub user: String,
+    pub sound: String,
+    pub clip: String,
+    /// Image of the sound, a file in the overlay directory or a URL.
+    pub image: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Page shell; `{{style}}` and `{{template}}` are replaced by the CSS and
+/// the alert markup.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<style>
+{{style}}
+</style>
+</head>
+<body>
+<template id="alert">{{template}}</template>
+<div id="alerts"></div>
+<script>
+const template = document.getElementById("alert").innerHTML;
+const escape = (value) =>
+  String(value ?? "").replace(/[&<>"']/g, (c) => "&#" + c.charCodeAt(0) + ";");
+const events = new EventSource("/overlay/events");
+events.addEventListener("start", (message) => {
+  const alert = JSON.parse(message.data);
+  const element = document.createElement("div");
+  element.className = "alert";
+  element.id = "alert-" + alert.id;
+  element.innerHTML = template.replace(/\{\{(\w+)\}\}/g, (_, key) =>
+    escape(alert[key]));
+  document.getElementById("alerts").appendChild(element);
+});
+events.addEventListener("end", (message) => {
+  const alert = JSON.parse(message.data);
+  document.getElementById("alert-" + alert.id)?.remove();
+});
+</script>
+</body>
+</html>
+"#;
+
+/// Alert markup used when the overlay directory has no `template.html`.
+const DEFAULT_TEMPLATE: &str = r#"<img src="{{image}}" alt="">
+<p><strong>{{user}}</strong> redeemed <strong>{{sound}}</strong></p>"#;
+
+/// Styles used when the overlay directory has no `style.css`.
+const DEFAULT_STYLE: &str = r#"body { margin: 0; background: transparent; }
+.alert { font: bold 32px sans-serif; color: white; text-align: center;
+  text-shadow: 0 0 6px black; }
+.alert img { max-height: 240px; }
+.alert img[src=""] { display: none; }"#;
+
+/// Directory with the overlay's template, styles and images.
+fn overlay_dir() -> PathBuf {
+    PathBuf::from(env::var("OVERLAY_DIR").unwrap_or_else(|_| "overlay".into()))
+}
+
+/// Builds the page from the overlay directory, falling back to the
+/// built-in layout. Read on every request so edits show on refresh.
+fn render_page(dir: &std::path::Path) -> String {
+    let read = |file: &str, default: &str| {
+        std::fs::read_to_string(dir.join(file))
+            .unwrap_or_else(|_| default.to_string())
+    };
+    PAGE.replace("{{style}}", &read("style.css", DEFAULT_STYLE))
+        .replace("{{template}}", &read("template.html", DEFAULT_TEMPLATE))
+}
+
+/// URL the browser loads an image from.
+fn image_url(image: &str) -> String {
+    if image.starts_with("http://") || image.starts_with("https://") {
+        image.to_string()
+    } else {
+        format!("/overlay/files/{}", image)
+    }
+}
+
+async fn page() -> Html<String> {
+    Html(render_page(&overlay_dir()))
+}
+
+/// Streams clip events as Server-Sent Events named `start` and `end`.
+async fn events(
+    State(player): State<&'static Player>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = player.subscribe();
+    let stream =
+        futures_util::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(mut event) => {
+                        event.image = event.image.as_deref().map(image_url);
+                        let name = match event.phase {
+                            Phase::Start => "start",
+                            Phase::End => "end",
+                        };
+                        let Ok(data) =
+                            Event::default().event(name).json_data(&event)
+                        else {
+                            continue;
+                        };
+                        return Some((Ok(data), receiver));
+                    }
+                    // A slow browser misses events rather than stalling.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Content type of an overlay file, from its extension.
+fn content_type(file: &str) -> &'static str {
+    let extension = file.rsplit('.').next().unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "webm" => "video/webm",
+        "mp4" => "video/mp4",
+        "css" => "text/css",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves a file from the overlay directory. Only plain file names are
+/// accepted so requests cannot reach outside of it.
+async fn file(Path(file): Path<String>) -> Response {
+    if file.starts_with('.') || file.contains(['/', '\\']) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match tokio::fs::read(overlay_dir().join(&file)).await {
+        Ok(data) => ([(header::CONTENT_TYPE, content_type(&file))], data)
+            .into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Routes of the OBS browser source overlay. They need no token, since the
+/// overlay only shows what is already on stream.
+pub fn router(player: &'static Player) -> Router {
+    Router::new()
+        .route("/overlay", get(page))
+        .route("/overlay/events", get(events))
+        .route("/overlay/files/{file}", get(file))
+        .with_state(player)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renders_custom_template_and_default_style() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("template.html"), "<b>{{user}}</b>")
+            .unwrap();
+        let page = render_page(dir.path());
+        assert!(page.contains(r#"<template id="alert"><b>{{user}}</b>"#));
+        assert!(page.contains(DEFAULT_STYLE));
+    }
+
+    #[test]
+    fn test_maps_images_to_urls() {
+        assert_eq!(image_url("horn.gif"), "/overlay/files/horn.gif");
+        assert_eq!(image_url("https://x.y/a.png"), "https://x.y/a.png");
+        assert_eq!(content_type("Horn.GIF"), "image/gif");
+    }
+}
@@ -0,0 +1,131 @@
+use rand::distr::weighted::WeightedIndex;
+use rand::distr::Distribution;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// How a pool picks the clip to play next.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolStrategy {
+    /// Every clip is equally likely each time.
+    #[default]
+    Uniform,
+    /// Clips are picked in proportion to their configured weight.
+    Weighted,
+    /// Every clip plays once, in random order, before any repeats.
+    Shuffle,
+    /// Clips play in alphabetical order, starting over at the end.
+    RoundRobin,
+}
+
+/// Selection state of one pool that persists between plays.
+#[derive(Default)]
+pub struct PoolState {
+    next: usize,
+    bag: Vec<usize>,
+    last: Option<usize>,
+}
+
+impl PoolState {
+    /// Picks the index of the next clip among `names`, which must not be
+    /// empty. `weights` is only used by the weighted strategy; clips without
+    /// a weight count as 1.
+    pub fn choose(
+        &mut self,
+        strategy: PoolStrategy,
+        names: &[String],
+        weights: &HashMap<String, f32>,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let len = names.len();
+        let index = match strategy {
+            PoolStrategy::Uniform => rng.random_range(0..len),
+            PoolStrategy::Weighted => {
+                let weights: Vec<f32> = names
+                    .iter()
+                    .map(|name| {
+                        weights
+                            .iter()
+                            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                            .map(|(_, weight)| weight.max(0.0))
+                            .unwrap_or(1.0)
+                    })
+                    .collect();
+                match WeightedIndex::new(&weights) {
+                    Ok(dist) => dist.sample(rng),
+                    // All weights zero: fall back to a uniform pick.
+                    Err(_) => rng.random_range(0..len),
+                }
+            }
+            PoolStrategy::Shuffle => {
+                if self.bag.is_empty() {
+                    self.bag = (0..len).collect();
+                    self.bag.shuffle(rng);
+                    // Do not repeat the previous clip across bag refills.
+                    if len > 1 && self.bag.last() == self.last.as_ref() {
+                        self.bag.swap(0, len - 1);
+                    }
+                }
+                self.bag.pop().unwrap()
+            }
+            PoolStrategy::RoundRobin => {
+                let index = self.next % len;
+                self.next = index + 1;
+                index
+            }
+        };
+        self.last = Some(index);
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn names() -> Vec<String> {
+        ["a", "b", "c"].iter().map(|s| s.to_string()).collect()
+    }
+
+    fn picks(
+        strategy: PoolStrategy,
+        weights: &HashMap<String, f32>,
+        n: usize,
+    ) -> Vec<usize> {
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        let mut state = PoolState::default();
+        (0..n)
+            .map(|_| state.choose(strategy, &names(), weights, &mut rng))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin_cycles_in_order() {
+        let picked = picks(PoolStrategy::RoundRobin, &HashMap::new(), 5);
+        assert_eq!(picked, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_shuffle_plays_each_clip_once_per_round() {
+        let picked = picks(PoolStrategy::Shuffle, &HashMap::new(), 30);
+        for round in picked.chunks(3) {
+            let mut sorted = round.to_vec();
+            sorted.sort();
+            assert_eq!(sorted, vec![0, 1, 2]);
+        }
+        assert!(picked.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn test_weighted_skips_zero_weights_and_is_deterministic() {
+        let weights =
+            HashMap::from([("A".to_string(), 0.0), ("b".to_string(), 3.0)]);
+        let picked = picks(PoolStrategy::Weighted, &weights, 50);
+        assert!(!picked.contains(&0));
+        assert_eq!(picked, picks(PoolStrategy::Weighted, &weights, 50));
+    }
+}
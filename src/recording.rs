@@ -0,0 +1,159 @@
+use crate::config::data_path;
+use crate::eventsub::dispatch_message;
+use crate::helix::Helix;
+use crate::history;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::future::join_all;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// A line of a recording: one raw WebSocket message and when it arrived.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Recorded {
+    /// RFC 3339 time of arrival, in milliseconds.
+    at: String,
+    message: String,
+}
+
+/// The file messages are recorded to, once recording started.
+static RECORDING: OnceCell<Mutex<LineWriter<File>>> = OnceCell::new();
+
+/// Starts recording every received EventSub message to a new file next to
+/// the config, named after the current time.
+pub fn start() -> Result<(), Box<dyn Error>> {
+    let name = format!("eventsub-{}.jsonl", Utc::now().format("%Y%m%d-%H%M%S"));
+    let path = data_path(&name)?;
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    RECORDING
+        .set(Mutex::new(LineWriter::new(file)))
+        .map_err(|_| "Recording already started")?;
+    info!(path = %path.display(), "Recording EventSub messages.");
+    Ok(())
+}
+
+/// Appends a received message to the recording, if one is running.
+pub fn record(message: &str) {
+    let Some(file) = RECORDING.get() else {
+        return;
+    };
+    let line = Recorded {
+        at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        message: message.to_string(),
+    };
+    let result = serde_json::to_string(&line)
+        .map_err(Into::into)
+        .and_then(|line| writeln!(file.lock().unwrap(), "{}", line));
+    if let Err(e) = result {
+        warn!("Failed to record EventSub message: {}", e);
+    }
+}
+
+/// Writes out whatever is left of the recording.
+pub fn flush() {
+    if let Some(file) = RECORDING.get() {
+        if let Err(e) = file.lock().unwrap().flush() {
+            warn!("Failed to flush the recording: {}", e);
+        }
+    }
+}
+
+/// Reads a recording, one message per line. Blank lines are skipped.
+fn read(data: &str) -> Result<Vec<Recorded>, Box<dyn Error>> {
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                format!("Line {} is not a recording: {}", i + 1, e).into()
+            })
+        })
+        .collect()
+}
+
+/// How long to wait between messages recorded at `previous` and `at` when
+/// replaying `speed` times as fast. A speed of 0 replays without pauses.
+fn delay(previous: Option<&str>, at: &str, speed: f64) -> Option<Duration> {
+    if speed <= 0.0 {
+        return None;
+    }
+    let previous = DateTime::parse_from_rfc3339(previous?).ok()?;
+    let at = DateTime::parse_from_rfc3339(at).ok()?;
+    let gap = (at - previous).to_std().ok()?;
+    Duration::try_from_secs_f64(gap.as_secs_f64() / speed).ok()
+}
+
+/// Feeds a recording through the same handlers as live events, `speed`
+/// times as fast as it was recorded. Nothing is sent to Twitch and nothing
+/// is stored in the history.
+pub async fn replay(path: &Path, speed: f64) -> Result<(), Box<dyn Error>> {
+    if speed.is_nan() || speed < 0.0 {
+        return Err("The replay speed must be 0 or more".into());
+    }
+    let messages = read(&fs::read_to_string(path)?)?;
+    history::disable();
+    let helix = Helix::offline();
+    let mut handlers = Vec::new();
+    let mut previous = None;
+    for recorded in &messages {
+        if let Some(wait) = delay(previous, &recorded.at, speed) {
+            tokio::time::sleep(wait).await;
+        }
+        previous = Some(&recorded.at);
+        let event: Value = match serde_json::from_str(&recorded.message) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(at = %recorded.at, "Skipping unreadable message: {}", e);
+                continue;
+            }
+        };
+        handlers.extend(dispatch_message(&event, |_| helix.clone()));
+    }
+    let handled = handlers.len();
+    join_all(handlers).await;
+    info!(messages = messages.len(), handled, "Replay finished.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_recorded_messages() {
+        let line = Recorded {
+            at: "2026-10-18T12:00:00.000Z".to_string(),
+            message: r#"{"metadata":{"message_type":"session_keepalive"}}"#
+                .to_string(),
+        };
+        let data = format!("{}\n\n", serde_json::to_string(&line).unwrap());
+        assert_eq!(read(&data).unwrap(), vec![line]);
+
+        let error = read("{}\nnot json").unwrap_err().to_string();
+        assert!(error.starts_with("Line 1 is not a recording"), "{}", error);
+    }
+
+    #[test]
+    fn test_scales_delays_by_speed() {
+        let first = "2026-10-18T12:00:00.000Z";
+        let second = "2026-10-18T12:00:03.000Z";
+        assert_eq!(delay(None, first, 1.0), None);
+        assert_eq!(
+            delay(Some(first), second, 1.0),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            delay(Some(first), second, 4.0),
+            Some(Duration::from_millis(750))
+        );
+        assert_eq!(delay(Some(first), second, 0.0), None);
+        assert_eq!(delay(Some(second), first, 1.0), None);
+    }
+}
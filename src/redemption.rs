@@ -1,8 +1,197 @@
+use crate::channels::{self, Channel};
+use crate::config::{load_settings, Settings};
+use crate::eventsub::{has_role, update_redemption_status};
+use crate::helix::Helix;
+use crate::history::{self, Entry, HistoryOutcome};
+use crate::metrics;
+use crate::sound::{EventKind, Outcome};
 use axum::http::StatusCode;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::{info, info_span, warn, Instrument, Span};
 
-/// Handles a channel point redemption event.
-pub fn handle_redemption(payload: Value) -> Result<(), StatusCode> {
+/// A viewer's role in the channel, used to limit who may redeem a reward.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Broadcaster,
+    Moderator,
+    Vip,
+    Subscriber,
+}
+
+/// Moderation options in `settings.json`.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ModerationSettings {
+    /// Viewers (login or user id) whose redemptions are ignored.
+    pub blocked_users: Vec<String>,
+    /// Words that block a redemption when its message contains them,
+    /// matched as whole words ignoring case.
+    pub blocked_words: Vec<String>,
+    /// Regular expressions that block a redemption when they match its
+    /// message.
+    pub blocked_patterns: Vec<String>,
+    /// Whether blocked redemptions are refunded.
+    pub refund_blocked: bool,
+}
+
+/// Decides which redemptions may play, from the moderation settings and the
+/// allow lists of the rewards.
+pub struct Moderation {
+    settings: Settings,
+    patterns: Vec<Regex>,
+}
+
+/// Whether a viewer is on a list of logins and user ids.
+fn is_listed(list: &[String], user_id: &str, user_login: &str) -> bool {
+    list.iter()
+        .any(|entry| entry == user_id || entry.eq_ignore_ascii_case(user_login))
+}
+
+impl Moderation {
+    /// Compiles the blocked patterns, skipping invalid ones with a warning.
+    pub fn new(settings: Settings) -> Moderation {
+        let patterns = settings
+            .moderation
+            .blocked_patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    warn!(
+                        pattern = %pattern,
+                        "Ignoring invalid blocked pattern: {}", e
+                    );
+                    None
+                }
+            })
+            .collect();
+        Moderation { settings, patterns }
+    }
+
+    /// Whether blocked redemptions are refunded.
+    pub fn refunds_blocked(&self) -> bool {
+        self.settings.moderation.refund_blocked
+    }
+
+    /// Checks the block list and the message filters. Returns why the
+    /// redemption is blocked, if it is.
+    pub fn screen(
+        &self,
+        user_id: &str,
+        user_login: &str,
+        input: &str,
+    ) -> Option<String> {
+        let moderation = &self.settings.moderation;
+        if is_listed(&moderation.blocked_users, user_id, user_login) {
+            return Some("viewer is blocked".to_string());
+        }
+        let has_blocked_word =
+            input.split(|c: char| !c.is_alphanumeric()).any(|word| {
+                moderation
+                    .blocked_words
+                    .iter()
+                    .any(|blocked| blocked.eq_ignore_ascii_case(word))
+            });
+        if has_blocked_word {
+            return Some("message contains a blocked word".to_string());
+        }
+        if self.patterns.iter().any(|regex| regex.is_match(input)) {
+            return Some("message matches a blocked pattern".to_string());
+        }
+        None
+    }
+
+    /// Roles of which the viewer needs one to redeem `reward`, or `None` if
+    /// they may redeem it anyway: the reward has no allow list, or the
+    /// viewer is on it.
+    pub fn required_roles(
+        &self,
+        reward: &str,
+        user_id: &str,
+        user_login: &str,
+    ) -> Option<&[Role]> {
+        let sound = self.settings.sound(reward)?;
+        if sound.allowed_roles.is_empty() && sound.allowed_users.is_empty() {
+            return None;
+        }
+        if is_listed(&sound.allowed_users, user_id, user_login) {
+            return None;
+        }
+        Some(&sound.allowed_roles)
+    }
+}
+
+/// A handled redemption, as listed by the control API.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentRedemption {
+    /// Unix time in seconds.
+    pub at: u64,
+    pub user: String,
+    pub reward: String,
+    pub input: String,
+    pub outcome: Outcome,
+}
+
+/// How many redemptions are remembered for the control API.
+const MAX_RECENT: usize = 50;
+
+static RECENT: Lazy<Mutex<VecDeque<RecentRedemption>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Remembers a redemption, dropping the oldest beyond `MAX_RECENT`, and
+/// counts its outcome.
+fn record(event: &Value, outcome: Outcome) {
+    metrics::REDEMPTIONS.inc_with(outcome.as_str());
+    let at = history::now();
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == MAX_RECENT {
+        recent.pop_front();
+    }
+    recent.push_back(RecentRedemption {
+        at,
+        user: field(event, &["user_login"])
+            .unwrap_or_default()
+            .to_string(),
+        reward: field(event, &["reward", "title"])
+            .unwrap_or_default()
+            .to_string(),
+        input: field(event, &["user_input"])
+            .unwrap_or_default()
+            .to_string(),
+        outcome,
+    });
+}
+
+/// The most recent redemptions, newest first.
+pub fn recent_redemptions() -> Vec<RecentRedemption> {
+    RECENT.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Moderation rules, read from `settings.json` once.
+static MODERATION: Lazy<Moderation> =
+    Lazy::new(|| Moderation::new(load_settings()));
+
+/// Moderation rules of the `BROADCASTER_ID` channel.
+pub fn moderation() -> &'static Moderation {
+    &MODERATION
+}
+
+/// Reads a string field of the event, if present.
+pub fn field<'a>(event: &'a Value, path: &[&str]) -> Option<&'a str> {
+    path.iter()
+        .try_fold(event, |value, key| value.get(key))
+        .and_then(|value| value.as_str())
+}
+
+/// Handles a channel point redemption event with the player of its channel.
+pub fn handle_redemption(payload: Value) -> Result<Outcome, StatusCode> {
     // Attempt to extract the "event" object from the payload.
     if let Some(event) = payload.get("event") {
         // Extract the reward title (if available).
@@ -18,17 +207,212 @@ pub fn handle_redemption(payload: Value) -> Result<(), StatusCode> {
             .and_then(|u| u.as_str())
             .unwrap_or("unknown user");
 
-        crate::sound::play_sound_for_redemption(user_name, reward_title);
+        // The viewer's message, for rewards that ask for one.
+        let user_input = event
+            .get("user_input")
+            .and_then(|u| u.as_str())
+            .unwrap_or_default();
+        // When the viewer redeemed it, to measure the playback latency.
+        let redeemed_at = event
+            .get("redeemed_at")
+            .and_then(|r| r.as_str())
+            .and_then(|r| chrono::DateTime::parse_from_rfc3339(r).ok())
+            .map(SystemTime::from);
+
+        let broadcaster_id =
+            field(event, &["broadcaster_user_id"]).unwrap_or_default();
+        Ok(channels::for_broadcaster(broadcaster_id).player.redeem(
+            user_name,
+            reward_title,
+            user_input,
+            EventKind::Redemption,
+            redeemed_at,
+        ))
     } else {
-        println!("No event details found in the payload.");
+        warn!("No event details found in the payload.");
+        Ok(Outcome::Failed)
+    }
+}
+
+/// Screens a redemption against the block lists, message filters and the
+/// allow lists of the reward and of the sound picked from its message,
+/// looking up the viewer's roles on Twitch if needed.
+/// Returns why the redemption is blocked, if it is.
+async fn moderate(
+    channel: &Channel,
+    event: &Value,
+    helix: &Helix,
+) -> Option<String> {
+    let user_id = field(event, &["user_id"]).unwrap_or_default();
+    let user_login = field(event, &["user_login"]).unwrap_or_default();
+    let input = field(event, &["user_input"]).unwrap_or_default();
+    let reward = field(event, &["reward", "title"]).unwrap_or_default();
+    let moderation = channel.moderation;
+    if let Some(reason) = moderation.screen(user_id, user_login, input) {
+        return Some(reason);
+    }
+
+    if let Some(reason) = check_roles(channel, event, helix, reward).await {
+        return Some(reason);
+    }
+    // Rewards that take the sound from the message are also limited by the
+    // allow list of the sound that will play.
+    let takes_input = moderation
+        .settings
+        .sound(reward)
+        .is_some_and(|sound| sound.sound_from_input);
+    let sound = channel
+        .player
+        .resolve_input(input)
+        .filter(|_| takes_input)?;
+    check_roles(channel, event, helix, sound).await
+}
+
+/// Checks the allow list of the sound `name`, looking up the viewer's roles
+/// on Twitch if needed. Returns why the redemption is blocked, if it is.
+async fn check_roles(
+    channel: &Channel,
+    event: &Value,
+    helix: &Helix,
+    name: &str,
+) -> Option<String> {
+    let user_id = field(event, &["user_id"]).unwrap_or_default();
+    let user_login = field(event, &["user_login"]).unwrap_or_default();
+    let roles = channel
+        .moderation
+        .required_roles(name, user_id, user_login)?;
+    let broadcaster_id =
+        field(event, &["broadcaster_user_id"]).unwrap_or_default();
+    for role in roles {
+        match has_role(helix, broadcaster_id, user_id, *role).await {
+            Ok(true) => return None,
+            Ok(false) => {}
+            // A replay cannot look roles up; blocking would hide what
+            // actually happened.
+            Err(_) if helix.is_offline() => {
+                info!(role = ?role, "Replaying, skipping the role check.");
+                return None;
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+    Some("viewer has none of the allowed roles".to_string())
+}
+
+/// Cancels a redemption so the viewer gets their points back. Returns
+/// whether that worked.
+async fn refund(event: &Value, helix: &Helix) -> bool {
+    let (Some(broadcaster_id), Some(reward_id), Some(redemption_id)) = (
+        field(event, &["broadcaster_user_id"]),
+        field(event, &["reward", "id"]),
+        field(event, &["id"]),
+    ) else {
+        warn!("Redemption is missing the ids needed to refund it.");
+        return false;
+    };
+    match update_redemption_status(
+        helix,
+        broadcaster_id,
+        reward_id,
+        redemption_id,
+        "CANCELED",
+    )
+    .await
+    {
+        Ok(()) => {
+            info!("Refunded redemption.");
+            true
+        }
+        Err(e) => {
+            warn!("Failed to refund redemption: {}", e);
+            false
+        }
+    }
+}
+
+/// Moderates a redemption, handles it off the async runtime since playback
+/// blocks, and reports the outcome back to Twitch: redemptions whose
+/// requested sound does not exist are refunded, and so are blocked ones if
+/// `refund_blocked` is set. Every redemption is stored in the history.
+pub async fn dispatch(payload: Value, helix: Helix) {
+    let event = payload.get("event").cloned().unwrap_or_default();
+    let channel = channels::for_broadcaster(
+        field(&event, &["broadcaster_user_id"]).unwrap_or_default(),
+    );
+    let span = info_span!(
+        "redemption",
+        channel = %channel.login,
+        redemption_id = field(&event, &["id"]).unwrap_or_default(),
+        reward = field(&event, &["reward", "title"]).unwrap_or_default(),
+        user = field(&event, &["user_login"]).unwrap_or_default()
+    );
+    let (outcome, refunded) = async {
+        if let Some(reason) = moderate(channel, &event, &helix).await {
+            info!(reason, "Blocked redemption.");
+            let refunded = channel.moderation.refunds_blocked()
+                && refund(&event, &helix).await;
+            return (Outcome::Blocked, refunded);
+        }
+        // The blocking thread doesn't inherit the span; carry it over so
+        // the player's log lines keep the redemption's fields.
+        let span = Span::current();
+        let outcome = tokio::task::spawn_blocking(move || {
+            span.in_scope(|| handle_redemption(payload))
+        })
+        .await;
+        let outcome = match outcome {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(status)) => {
+                warn!(%status, "Failed to handle redemption.");
+                Outcome::Failed
+            }
+            Err(e) => {
+                warn!("Redemption handler failed: {}", e);
+                Outcome::Failed
+            }
+        };
+        let refunded =
+            outcome == Outcome::NoMatch && refund(&event, &helix).await;
+        (outcome, refunded)
     }
-    Ok(())
+    .instrument(span)
+    .await;
+    record(&event, outcome.clone());
+    crate::chat::report(&event, outcome.clone(), &helix).await;
+
+    let text =
+        |path: &[&str]| field(&event, path).unwrap_or_default().to_string();
+    let input = text(&["user_input"]);
+    let reward_title = text(&["reward", "title"]);
+    let sound = match &outcome {
+        Outcome::Handled { clip } => clip.clone(),
+        _ => None,
+    };
+    let entry = Entry {
+        at: history::now(),
+        user_id: text(&["user_id"]),
+        user_login: text(&["user_login"]),
+        reward_id: text(&["reward", "id"]),
+        reward_title,
+        input,
+        sound,
+        outcome: HistoryOutcome::new(&outcome, refunded).as_str().to_string(),
+    };
+    tokio::task::spawn_blocking(move || history::record(&entry))
+        .await
+        .ok();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio::tests::write_tone;
+    use crate::audio::NullBackend;
+    use crate::chat::ChatSettings;
+    use crate::sound::Player;
     use serde_json::json;
+    use std::path::Path;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_handle_redemption() {
@@ -44,4 +428,126 @@ mod tests {
         let result = handle_redemption(payload);
         assert!(result.is_ok());
     }
+
+    fn moderation() -> Moderation {
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "moderation": {
+                    "blocked_users": ["Troll", "666"],
+                    "blocked_words": ["darn"],
+                    "blocked_patterns": ["(?i)https?://", "(unclosed"]
+                },
+                "sounds": {
+                    "VIP Horn": {
+                        "allowed_roles": ["vip", "subscriber"],
+                        "allowed_users": ["friend"]
+                    },
+                    "Mods Only": { "allowed_users": ["42"] }
+                }
+            }"#,
+        )
+        .unwrap();
+        Moderation::new(settings)
+    }
+
+    #[test]
+    fn test_screens_blocked_users_and_messages() {
+        let moderation = moderation();
+        assert!(moderation.screen("1", "troll", "").is_some());
+        assert!(moderation.screen("666", "renamed", "").is_some());
+        assert!(moderation.screen("1", "viewer", "well DARN it").is_some());
+        assert!(moderation.screen("1", "viewer", "see HTTP://x.y").is_some());
+        // Blocked words only match whole words.
+        assert_eq!(moderation.screen("1", "viewer", "darning socks"), None);
+    }
+
+    #[test]
+    fn test_allow_lists_require_roles_unless_listed() {
+        let moderation = moderation();
+        assert_eq!(moderation.required_roles("Other", "1", "viewer"), None);
+        assert_eq!(moderation.required_roles("vip horn", "1", "Friend"), None);
+        assert_eq!(
+            moderation.required_roles("VIP Horn", "1", "viewer"),
+            Some(&[Role::Vip, Role::Subscriber][..])
+        );
+        assert_eq!(moderation.required_roles("Mods Only", "42", "x"), None);
+        assert_eq!(
+            moderation.required_roles("Mods Only", "1", "viewer"),
+            Some(&[][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replays_skip_role_lookups() {
+        let channel = Channel {
+            login: "test".to_string(),
+            player: Box::leak(Box::new(Player::new(
+                Path::new("/nonexistent"),
+                Box::new(NullBackend::new()),
+                Settings::default(),
+            ))),
+            moderation: Box::leak(Box::new(moderation())),
+            chat: Box::leak(Box::new(ChatSettings::default())),
+        };
+        let event = |reward: &str| {
+            json!({
+                "user_id": "1",
+                "user_login": "viewer",
+                "broadcaster_user_id": "9",
+                "reward": { "title": reward }
+            })
+        };
+        let helix = Helix::offline();
+        assert_eq!(moderate(&channel, &event("VIP Horn"), &helix).await, None);
+        // Allow lists without roles need no lookup and still apply.
+        assert!(moderate(&channel, &event("Mods Only"), &helix)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_sounds_picked_from_input_keep_their_allow_list() {
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "sounds": {
+                    "Any Sound": { "sound_from_input": true },
+                    "Mods Only": { "allowed_users": ["42"] }
+                }
+            }"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["Mods Only", "Boom"] {
+            let path = dir.path().join(format!("{}.wav", name));
+            write_tone(&path, Duration::from_millis(10));
+        }
+        let channel = Channel {
+            login: "test".to_string(),
+            player: Box::leak(Box::new(Player::new(
+                dir.path(),
+                Box::new(NullBackend::new()),
+                settings.clone(),
+            ))),
+            moderation: Box::leak(Box::new(Moderation::new(settings))),
+            chat: Box::leak(Box::new(ChatSettings::default())),
+        };
+        let event = |user_id: &str, input: &str| {
+            json!({
+                "user_id": user_id,
+                "user_login": "viewer",
+                "user_input": input,
+                "broadcaster_user_id": "9",
+                "reward": { "title": "Any Sound" }
+            })
+        };
+        let helix = Helix::offline();
+        assert!(moderate(&channel, &event("1", "mods only"), &helix)
+            .await
+            .is_some());
+        assert_eq!(
+            moderate(&channel, &event("42", "mods only"), &helix).await,
+            None
+        );
+        assert_eq!(moderate(&channel, &event("1", "boom"), &helix).await, None);
+    }
 }
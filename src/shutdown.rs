@@ -0,0 +1,234 @@
+use once_cell::sync::Lazy;
+use std::env;
+use std::io::Write;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What to wait for before exiting once shutdown has begun.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrainMode {
+    /// Let the clips that are already playing finish, drop everything else.
+    Current,
+    /// Let every accepted event finish, including ones not yet playing.
+    Queue,
+}
+
+impl DrainMode {
+    /// Reads `SHUTDOWN_DRAIN` (`current` or `queue`), defaulting to current.
+    pub fn from_env() -> DrainMode {
+        match env::var("SHUTDOWN_DRAIN").as_deref() {
+            Ok("queue") => DrainMode::Queue,
+            _ => DrainMode::Current,
+        }
+    }
+}
+
+/// How long to wait for playback to drain, from `SHUTDOWN_TIMEOUT_SECS`.
+pub fn drain_timeout() -> Duration {
+    let secs = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    Duration::from_secs(secs)
+}
+
+/// Tracks in-flight events and playing clips so shutdown can stop accepting
+/// new work and wait for the existing work to finish.
+pub struct Coordinator {
+    shutting_down: AtomicBool,
+    /// Set when playback was cut short, by the drain timeout or a second
+    /// signal.
+    timed_out: AtomicBool,
+    drain_mode: Mutex<DrainMode>,
+    in_flight: AtomicUsize,
+    playing: AtomicUsize,
+}
+
+/// Decrements the in-flight event count when dropped.
+pub struct EventGuard<'a>(&'a Coordinator);
+
+impl Drop for EventGuard<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Decrements the playing clip count when dropped.
+pub struct PlaybackGuard<'a>(&'a Coordinator);
+
+impl Drop for PlaybackGuard<'_> {
+    fn drop(&mut self) {
+        self.0.playing.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Coordinator {
+    pub fn new() -> Coordinator {
+        Coordinator {
+            shutting_down: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+            drain_mode: Mutex::new(DrainMode::Current),
+            in_flight: AtomicUsize::new(0),
+            playing: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting new events from now on.
+    pub fn begin(&self, mode: DrainMode) {
+        *self.drain_mode.lock().unwrap() = mode;
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Registers an incoming event. Returns `None` once shutdown has begun.
+    pub fn accept_event(&self) -> Option<EventGuard<'_>> {
+        if self.is_shutting_down() {
+            return None;
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(EventGuard(self))
+    }
+
+    /// Registers a clip that is about to start. Returns `None` if shutdown
+    /// has begun and only the clips already playing may finish, or if the
+    /// drain was cut short.
+    pub fn start_playback(&self) -> Option<PlaybackGuard<'_>> {
+        if self.timed_out.load(Ordering::SeqCst)
+            || (self.is_shutting_down()
+                && *self.drain_mode.lock().unwrap() == DrainMode::Current)
+        {
+            return None;
+        }
+        self.playing.fetch_add(1, Ordering::SeqCst);
+        Some(PlaybackGuard(self))
+    }
+
+    /// Number of events accepted but not yet handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Number of clips playing right now.
+    pub fn playing(&self) -> usize {
+        self.playing.load(Ordering::SeqCst)
+    }
+
+    fn is_drained(&self) -> bool {
+        match *self.drain_mode.lock().unwrap() {
+            DrainMode::Current => self.playing.load(Ordering::SeqCst) == 0,
+            DrainMode::Queue => {
+                self.playing.load(Ordering::SeqCst) == 0
+                    && self.in_flight.load(Ordering::SeqCst) == 0
+            }
+        }
+    }
+
+    /// Waits until the work selected by the drain mode has finished or the
+    /// timeout elapses. Returns whether everything drained in time.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let drained = tokio::time::timeout(timeout, async {
+            while !self.is_drained() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .is_ok();
+        if !drained {
+            self.timed_out.store(true, Ordering::SeqCst);
+        }
+        drained
+    }
+
+    /// Records that a second signal ended the drain early.
+    pub fn interrupt(&self) {
+        self.timed_out.store(true, Ordering::SeqCst);
+    }
+
+    /// Flushes buffered output before the process exits.
+    pub fn flush(&self) {
+        std::io::stdout().flush().ok();
+        std::io::stderr().flush().ok();
+    }
+
+    /// Exit status for a run that ended without an error: 0 after a clean
+    /// shutdown, 2 if playback was still active when the drain timed out or
+    /// a second signal cut it short.
+    pub fn exit_code(&self) -> ExitCode {
+        if self.timed_out.load(Ordering::SeqCst) {
+            ExitCode::from(2)
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Process-wide shutdown coordinator.
+pub static COORDINATOR: Lazy<Coordinator> = Lazy::new(Coordinator::new);
+
+/// Resolves when the process receives Ctrl-C or, on Unix, SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refuses_events_after_shutdown_begins() {
+        let coordinator = Coordinator::new();
+        assert!(coordinator.accept_event().is_some());
+        coordinator.begin(DrainMode::Current);
+        assert!(coordinator.accept_event().is_none());
+        assert!(coordinator.start_playback().is_none());
+
+        assert_eq!(coordinator.exit_code(), ExitCode::SUCCESS);
+        coordinator.interrupt();
+        assert_eq!(coordinator.exit_code(), ExitCode::from(2));
+    }
+
+    #[tokio::test]
+    async fn test_queue_mode_waits_for_accepted_events() {
+        let coordinator = Coordinator::new();
+        let event = coordinator.accept_event().unwrap();
+        coordinator.begin(DrainMode::Queue);
+
+        // Accepted events may still start playing in queue mode.
+        let playback = coordinator.start_playback();
+        assert!(playback.is_some());
+        assert!(!coordinator.drain(Duration::from_millis(100)).await);
+        assert_eq!(coordinator.exit_code(), ExitCode::from(2));
+        // Once the drain timed out, nothing new may start.
+        assert!(coordinator.start_playback().is_none());
+
+        drop(playback);
+        drop(event);
+        assert!(coordinator.drain(Duration::from_millis(100)).await);
+    }
+}
@@ -1,107 +1,1051 @@
+use crate::audio::{backend_from_env, Backend, Clip, PlayOptions, Voice};
+use crate::cache::ClipCache;
+use crate::config::{data_path, load_settings, Settings};
+use crate::loudness::{Loudness, LoudnessCache};
+use crate::metrics;
+use crate::overlay::{OverlayEvent, Phase};
+use crate::pool::PoolState;
+use crate::shutdown::COORDINATOR;
+use crate::tts::{Tts, TtsMode};
 use once_cell::sync::Lazy;
-use rodio::{Decoder, OutputStream, Sink};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::io::BufReader;
-use std::sync::Mutex;
-
-/// Reads the list of available sound names from the "sounds" directory.
-fn read_sound_list() -> Vec<String> {
-    let mut list = Vec::new();
-    if let Ok(entries) = fs::read_dir("sounds") {
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// A playable sound: a single file, or a pool of clips in a subdirectory
+/// that one is picked from each time.
+struct Sound {
+    name: String,
+    /// Clip names and files, sorted by name. A plain file has exactly one.
+    clips: Vec<(String, PathBuf)>,
+}
+
+impl Sound {
+    fn is_pool(&self) -> bool {
+        self.clips.len() > 1
+    }
+}
+
+/// Name of a sound file: its file name up to the first dot.
+fn sound_name(fname: &str) -> Option<&str> {
+    fname.split('.').next().filter(|name| !name.is_empty())
+}
+
+/// Lists the sound files directly inside `dir` as (name, path) pairs.
+fn read_sound_files(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut files = Vec::new();
+    if let Ok(entries) = fs::read_dir(dir) {
         for entry in entries.flatten() {
             if let Ok(file_type) = entry.file_type() {
                 if file_type.is_file() {
                     if let Some(fname) = entry.file_name().to_str() {
-                        if let Some(name) = fname.split('.').next() {
-                            list.push(name.to_string());
+                        if let Some(name) = sound_name(fname) {
+                            files.push((name.to_string(), entry.path()));
                         }
                     }
                 }
             }
         }
     }
-    list
+    files.sort_by_key(|(name, _)| name.to_lowercase());
+    files
 }
 
-/// Cache the sound list once.
-static SOUND_LIST: Lazy<Mutex<Vec<String>>> =
-    Lazy::new(|| Mutex::new(read_sound_list()));
+/// Reads the available sounds from a directory, keyed by lowercase name.
+/// Files are single sounds; each subdirectory is a pool named after it.
+fn read_sound_index(dir: &Path) -> HashMap<String, Sound> {
+    let mut index = HashMap::new();
+    for (name, path) in read_sound_files(dir) {
+        let sound = Sound {
+            name: name.clone(),
+            clips: vec![(name.clone(), path)],
+        };
+        index.insert(name.to_lowercase(), sound);
+    }
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                let Some(name) = entry.file_name().to_str().map(String::from)
+                else {
+                    continue;
+                };
+                let clips = read_sound_files(&entry.path());
+                if !clips.is_empty() {
+                    index.insert(name.to_lowercase(), Sound { name, clips });
+                }
+            }
+        }
+    }
+    index
+}
 
-/// Plays a sound for a redemption event if the reward title matches one of the
-/// available sound files. The match is done case-insensitively. The decoded
-/// sound is appended to a new sink, and the thread will block until that sound
-/// finishes playing.
-pub fn play_sound_for_redemption(display_name: &str, reward_title: &str) {
-    println!("{} redeemed {}", display_name, reward_title);
+/// What caused a sound to be played, used to route it to a device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// A channel point redemption.
+    Redemption,
+    /// A sound played locally from the command line.
+    Test,
+    /// A sound triggered through the control API.
+    Api,
+    /// A sound requested with a chat command.
+    Chat,
+}
 
-    let sound_list = SOUND_LIST.lock().unwrap();
-    let lower_reward = reward_title.to_lowercase();
-    if let Some(matched_name) = sound_list
-        .iter()
-        .find(|name| name.to_lowercase() == lower_reward)
-    {
-        let file_path = format!("sounds/{}.mp3", matched_name);
-        // Attempt to play the sound file using rodio.
-        if let Ok((_stream, stream_handle)) = OutputStream::try_default() {
-            if let Ok(file) = File::open(&file_path) {
-                if let Ok(source) = Decoder::new(BufReader::new(file)) {
-                    let sink = Sink::try_new(&stream_handle)
-                        .expect("Failed to create sink");
-                    sink.append(source);
-                    // Block until the sound finishes playing.
-                    sink.sleep_until_end();
-                } else {
-                    println!("Failed to decode sound file: {}", file_path);
+impl EventKind {
+    /// Key of this event type in the `devices` settings.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Redemption => "redemption",
+            EventKind::Test => "test",
+            EventKind::Api => "api",
+            EventKind::Chat => "chat",
+        }
+    }
+}
+
+/// What came of a redemption, so it can be reported back to the viewer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// The redemption was handled. `clip` is the sound that played, as
+    /// `Pool/clip` for pools, if one did.
+    Handled { clip: Option<String> },
+    /// The viewer asked for a sound that does not exist.
+    NoMatch,
+    /// Alerts were paused, so nothing played.
+    Paused,
+    /// Moderation blocked the redemption.
+    Blocked,
+    /// The reward has no sound or speech set up, so nothing played.
+    Skipped,
+    /// The sound could not be played, for example because it failed to
+    /// decode.
+    Failed,
+}
+
+impl Outcome {
+    /// Name of the outcome, as serialized and in metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Handled { .. } => "handled",
+            Outcome::NoMatch => "no_match",
+            Outcome::Paused => "paused",
+            Outcome::Blocked => "blocked",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// Serialized by name, like in metrics.
+impl Serialize for Outcome {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Who and what a clip is played for, and when it was redeemed on Twitch,
+/// if it was.
+#[derive(Clone, Copy)]
+struct Trigger<'a> {
+    user: &'a str,
+    kind: EventKind,
+    redeemed_at: Option<SystemTime>,
+}
+
+/// A clip that is playing, as reported by the control API.
+#[derive(Clone, Debug, Serialize)]
+pub struct ActiveSound {
+    pub sound: String,
+    pub priority: i32,
+    pub volume: f32,
+    pub playing_ms: u64,
+}
+
+/// A clip that is currently playing and can be stopped or ducked.
+struct ActiveVoice {
+    id: u64,
+    sound: String,
+    priority: i32,
+    /// Volume the clip plays at when it is not ducked.
+    volume: f32,
+    started: Instant,
+    voice: Arc<dyn Voice>,
+}
+
+/// Matches reward titles against a sound directory and plays them through a
+/// backend.
+pub struct Player {
+    sounds: HashMap<String, Sound>,
+    backend: Box<dyn Backend>,
+    settings: Settings,
+    master_volume: f32,
+    normalize_dbfs: Option<f32>,
+    loudness: Mutex<Option<LoudnessCache>>,
+    clips: Mutex<ClipCache>,
+    rng: Mutex<ChaCha20Rng>,
+    pools: Mutex<HashMap<String, PoolState>>,
+    max_duration: Option<Duration>,
+    duck_gain: f32,
+    match_threshold: f64,
+    tts: Option<Tts>,
+    paused: AtomicBool,
+    events: broadcast::Sender<OverlayEvent>,
+    active: Mutex<Vec<ActiveVoice>>,
+    next_voice: AtomicU64,
+}
+
+/// Default memory budget of the decoded clip cache.
+const DEFAULT_CACHE_MB: usize = 64;
+
+/// Default similarity a sound name needs to be picked for a viewer's input.
+const DEFAULT_MATCH_THRESHOLD: f64 = 0.7;
+
+/// Default amount lower-priority sounds are turned down by, in dB.
+const DEFAULT_DUCK_DB: f32 = 12.0;
+
+/// Default longest time a clip may play before it is cut off.
+const DEFAULT_MAX_CLIP_SECS: f32 = 30.0;
+
+/// Fade-out used when a clip is cut off and its sound has no fade-out.
+const CUTOFF_FADE: Duration = Duration::from_millis(500);
+
+/// Reads a numeric setting from the environment.
+fn env_f32(key: &str) -> Option<f32> {
+    env::var(key).ok().and_then(|v| v.trim().parse().ok())
+}
+
+impl Player {
+    pub fn new(
+        sounds_dir: &Path,
+        backend: Box<dyn Backend>,
+        settings: Settings,
+    ) -> Player {
+        Player {
+            sounds: read_sound_index(sounds_dir),
+            backend,
+            settings,
+            master_volume: 1.0,
+            normalize_dbfs: None,
+            loudness: Mutex::new(None),
+            clips: Mutex::new(ClipCache::new(DEFAULT_CACHE_MB * 1024 * 1024)),
+            rng: Mutex::new(ChaCha20Rng::from_os_rng()),
+            pools: Mutex::new(HashMap::new()),
+            max_duration: None,
+            duck_gain: 10f32.powf(-DEFAULT_DUCK_DB / 20.0),
+            match_threshold: DEFAULT_MATCH_THRESHOLD,
+            tts: None,
+            paused: AtomicBool::new(false),
+            events: broadcast::channel(64).0,
+            active: Mutex::new(Vec::new()),
+            next_voice: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets how similar (0 to 1) a sound name must be to a viewer's input
+    /// to be played for it.
+    pub fn with_match_threshold(mut self, threshold: f64) -> Player {
+        self.match_threshold = threshold.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Speaks the messages of rewards that have text-to-speech enabled.
+    pub fn with_tts(mut self, tts: Tts) -> Player {
+        self.tts = Some(tts);
+        self
+    }
+
+    /// Turns sounds down by `db` decibels while a sound with a higher
+    /// priority plays.
+    pub fn with_ducking(mut self, db: f32) -> Player {
+        self.duck_gain = 10f32.powf(-db.max(0.0) / 20.0);
+        self
+    }
+
+    /// Cuts every clip off, with a fade-out, once it has played for
+    /// `duration`.
+    pub fn with_max_duration(mut self, duration: Duration) -> Player {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Seeds the random pool selection so picks are reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Player {
+        self.rng = Mutex::new(ChaCha20Rng::seed_from_u64(seed));
+        self
+    }
+
+    /// Keeps at most `bytes` of decoded samples in memory; 0 disables the
+    /// cache so every play decodes the file again.
+    pub fn with_cache_budget(mut self, bytes: usize) -> Player {
+        self.clips = Mutex::new(ClipCache::new(bytes));
+        self
+    }
+
+    /// Scales every sound by `volume` (linear, 1.0 leaves it unchanged).
+    pub fn with_master_volume(mut self, volume: f32) -> Player {
+        self.master_volume = volume.max(0.0);
+        self
+    }
+
+    /// Normalizes every clip to an RMS level of `target_dbfs`, measuring
+    /// each file once and keeping the results in `cache`.
+    pub fn with_normalization(
+        mut self,
+        target_dbfs: f32,
+        cache: LoudnessCache,
+    ) -> Player {
+        self.normalize_dbfs = Some(target_dbfs);
+        self.loudness = Mutex::new(Some(cache));
+        self
+    }
+
+    /// Builds the player from `SOUNDS_DIR` (default `sounds`), the audio
+    /// backend selected in the config, `settings.json`, `MASTER_VOLUME`,
+    /// `MAX_CLIP_SECS`, `DUCK_DB`, `SOUND_MATCH_THRESHOLD`, the
+    /// `NORMALIZE_*` settings and the `TTS_*` settings.
+    pub fn from_env() -> Player {
+        let dir = env::var("SOUNDS_DIR").unwrap_or_else(|_| "sounds".into());
+        Player::from_env_with(
+            Path::new(&dir),
+            backend_from_env(),
+            load_settings(),
+        )
+    }
+
+    /// Like [`Player::from_env`], but with the given sounds directory,
+    /// backend and settings, for the player of another channel.
+    pub fn from_env_with(
+        dir: &Path,
+        backend: Box<dyn Backend>,
+        settings: Settings,
+    ) -> Player {
+        let tts = Tts::from_env(&settings.tts);
+        let mut player = Player::new(dir, backend, settings)
+            .with_master_volume(env_f32("MASTER_VOLUME").unwrap_or(1.0));
+        if let Some(tts) = tts {
+            player = player.with_tts(tts);
+        }
+
+        let cache_mb = env::var("AUDIO_CACHE_MB")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_CACHE_MB);
+        player = player.with_cache_budget(cache_mb * 1024 * 1024);
+
+        player =
+            player.with_ducking(env_f32("DUCK_DB").unwrap_or(DEFAULT_DUCK_DB));
+        if let Some(threshold) = env_f32("SOUND_MATCH_THRESHOLD") {
+            player = player.with_match_threshold(threshold as f64);
+        }
+
+        // A limit of 0 lets clips play to the end.
+        let max_secs =
+            env_f32("MAX_CLIP_SECS").unwrap_or(DEFAULT_MAX_CLIP_SECS);
+        if max_secs > 0.0 {
+            player =
+                player.with_max_duration(Duration::from_secs_f32(max_secs));
+        }
+
+        if let Some(seed) = env::var("SOUND_POOL_SEED")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+        {
+            player = player.with_seed(seed);
+        }
+
+        let normalize = env::var("NORMALIZE_LOUDNESS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        if normalize {
+            let target = env_f32("NORMALIZE_TARGET_DBFS").unwrap_or(-20.0);
+            match data_path("loudness.json") {
+                Ok(path) => {
+                    player = player
+                        .with_normalization(target, LoudnessCache::load(path))
                 }
+                Err(e) => warn!("Loudness normalization disabled: {}", e),
+            }
+        }
+        player
+    }
+
+    /// Volume of a clip: the master volume times the sound's gain, times the
+    /// normalization gain when normalization is enabled. Clips without a
+    /// file, like speech, are measured every time.
+    fn volume_for(&self, name: &str, path: Option<&Path>, clip: &Clip) -> f32 {
+        let gain = self
+            .settings
+            .sound(name)
+            .and_then(|sound| sound.gain)
+            .unwrap_or(1.0);
+        let normalization = match self.normalize_dbfs {
+            Some(target) => {
+                let mut cache = self.loudness.lock().unwrap();
+                let loudness = match (cache.as_mut(), path) {
+                    (Some(cache), Some(path)) => {
+                        cache.get_or_analyze(path, clip)
+                    }
+                    _ => Loudness::analyze(clip),
+                };
+                loudness.normalization_gain(target)
+            }
+            None => 1.0,
+        };
+        self.master_volume * gain.max(0.0) * normalization
+    }
+
+    /// Device a sound should play on: the sound's own device, else the one
+    /// configured for the event type, else the backend default.
+    fn device_for(&self, name: &str, kind: EventKind) -> Option<&str> {
+        self.settings
+            .sound(name)
+            .and_then(|sound| sound.device.as_deref())
+            .or_else(|| {
+                self.settings.devices.get(kind.as_str()).map(String::as_str)
+            })
+    }
+
+    /// Playback options of a clip: its device, volume, fades and cut-off.
+    fn options_for<'a>(
+        &'a self,
+        name: &str,
+        path: Option<&Path>,
+        clip: &Clip,
+        kind: EventKind,
+    ) -> PlayOptions<'a> {
+        let settings = self.settings.sound(name);
+        let fade_in = settings.and_then(|s| s.fade_in_ms);
+        let mut fade_out = settings
+            .and_then(|s| s.fade_out_ms)
+            .map(Duration::from_millis);
+        let cut_off = self.max_duration.filter(|max| clip.duration() > *max);
+        if cut_off.is_some() && fade_out.is_none() {
+            fade_out = Some(CUTOFF_FADE);
+        }
+        PlayOptions {
+            device: self.device_for(name, kind),
+            volume: self.volume_for(name, path, clip),
+            fade_in: Duration::from_millis(fade_in.unwrap_or(0)),
+            fade_out: fade_out.unwrap_or_default(),
+            max_duration: cut_off,
+        }
+    }
+
+    /// Priority of a sound from its settings, 0 if it has none.
+    fn priority_for(&self, name: &str) -> i32 {
+        self.settings
+            .sound(name)
+            .and_then(|sound| sound.priority)
+            .unwrap_or(0)
+    }
+
+    /// Highest priority among the clips that are still playing.
+    fn top_priority(active: &[ActiveVoice]) -> Option<i32> {
+        active
+            .iter()
+            .filter(|a| !a.voice.is_finished())
+            .map(|a| a.priority)
+            .max()
+    }
+
+    /// The mixer: ducks every clip below the highest playing priority and
+    /// restores the others to their full volume.
+    fn remix(&self, active: &[ActiveVoice]) {
+        let top = Player::top_priority(active);
+        for playing in active {
+            let volume = if Some(playing.priority) < top {
+                playing.volume * self.duck_gain
             } else {
-                println!("Sound file not found: {}", file_path);
+                playing.volume
+            };
+            if playing.voice.volume() != volume {
+                playing.voice.set_volume(volume);
+            }
+        }
+    }
+
+    /// Pauses or resumes alerts. While paused, redemptions play nothing.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        info!(paused, "Alerts paused or resumed.");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Whether the audio output can be played to. Probes the devices, so it
+    /// may block for a moment.
+    pub fn audio_available(&self) -> bool {
+        self.backend.is_available()
+    }
+
+    /// Receives an event whenever a clip starts or ends, for the overlay.
+    pub fn subscribe(&self) -> broadcast::Receiver<OverlayEvent> {
+        self.events.subscribe()
+    }
+
+    /// Clips that are playing right now, oldest first.
+    pub fn active_sounds(&self) -> Vec<ActiveSound> {
+        self.active
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|a| !a.voice.is_finished())
+            .map(|a| ActiveSound {
+                sound: a.sound.clone(),
+                priority: a.priority,
+                volume: a.voice.volume(),
+                playing_ms: a.started.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+
+    /// Stops the most recently started clip that is still playing and
+    /// returns the name of its sound.
+    pub fn skip_current(&self) -> Option<String> {
+        let active = self.active.lock().unwrap();
+        let current = active.iter().rev().find(|a| !a.voice.is_finished())?;
+        current.voice.stop();
+        info!(sound = %current.sound, "Skipped sound.");
+        Some(current.sound.clone())
+    }
+
+    /// Writes state kept on disk, like the loudness cache, that has not been
+    /// saved yet.
+    pub fn flush(&self) {
+        if let Some(cache) = self.loudness.lock().unwrap().as_mut() {
+            cache.flush();
+        }
+    }
+
+    /// Stops every clip that is playing and returns how many were stopped.
+    pub fn stop_all(&self) -> usize {
+        let active = self.active.lock().unwrap();
+        let mut stopped = 0;
+        for playing in active.iter().filter(|a| !a.voice.is_finished()) {
+            playing.voice.stop();
+            stopped += 1;
+        }
+        info!(stopped, "Stopped all sounds.");
+        stopped
+    }
+
+    /// Returns the decoded clip for a file, from the cache if possible.
+    fn load_clip(&self, path: &Path) -> Result<Clip, Box<dyn Error>> {
+        if let Some(clip) = self.clips.lock().unwrap().get(path) {
+            return Ok(clip);
+        }
+        // Decode without holding the lock so other sounds are not blocked.
+        let clip = Clip::decode(path)?;
+        self.clips
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), clip.clone());
+        Ok(clip)
+    }
+
+    /// Decodes every sound once so broken files are reported at startup,
+    /// and keeps as many as fit in the cache budget. Returns the names of
+    /// the sounds that failed to decode.
+    pub fn prewarm(&self) -> Vec<String> {
+        let mut failed = Vec::new();
+        let mut cached = 0;
+        let files = self.sound_names().into_iter().flat_map(|name| {
+            // Pool clips are reported as `Pool/clip`.
+            let sound = &self.sounds[&name.to_lowercase()];
+            sound
+                .clips
+                .iter()
+                .map(|(clip, path)| {
+                    if sound.is_pool() {
+                        (format!("{}/{}", name, clip), path)
+                    } else {
+                        (name.clone(), path)
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        let total = self.sounds.values().map(|s| s.clips.len()).sum::<usize>();
+        for (name, path) in files {
+            match Clip::decode(path) {
+                Ok(clip) => {
+                    let size = clip.samples.len() * std::mem::size_of::<f32>();
+                    let mut clips = self.clips.lock().unwrap();
+                    // Prewarming never evicts, earlier sounds stay cached.
+                    if clips.has_room_for(size)
+                        && clips.insert(path.clone(), clip)
+                    {
+                        cached += 1;
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        sound = %name,
+                        file = ?path,
+                        "Failed to decode sound file: {}", e
+                    );
+                    failed.push(name);
+                }
+            }
+        }
+        info!(
+            cached,
+            total,
+            cache_bytes = self.clips.lock().unwrap().used(),
+            "Prewarmed sound cache."
+        );
+        failed
+    }
+
+    /// Picks the clip to play for a sound, using the pool's strategy.
+    fn pick_clip<'a>(&self, sound: &'a Sound) -> &'a (String, PathBuf) {
+        if !sound.is_pool() {
+            return &sound.clips[0];
+        }
+        let settings = self.settings.sound(&sound.name);
+        let strategy = settings.and_then(|s| s.strategy).unwrap_or_default();
+        let empty = HashMap::new();
+        let weights = settings.map(|s| &s.weights).unwrap_or(&empty);
+        let names: Vec<String> =
+            sound.clips.iter().map(|(name, _)| name.clone()).collect();
+
+        let mut pools = self.pools.lock().unwrap();
+        let state = pools.entry(sound.name.to_lowercase()).or_default();
+        let mut rng = self.rng.lock().unwrap();
+        let index = state.choose(strategy, &names, weights, &mut *rng);
+        &sound.clips[index]
+    }
+
+    /// Name of the sound called `name`, ignoring case.
+    pub fn find_sound(&self, name: &str) -> Option<&str> {
+        self.sounds
+            .get(&name.to_lowercase())
+            .map(|sound| sound.name.as_str())
+    }
+
+    /// Returns the sound names in alphabetical order.
+    pub fn sound_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .sounds
+            .values()
+            .map(|sound| sound.name.clone())
+            .collect();
+        names.sort_by_key(|name| name.to_lowercase());
+        names
+    }
+
+    /// Plays the sound `name` on its own, for requests that name a sound
+    /// rather than a reward: speech and `sound_from_input` settings do not
+    /// apply. Does nothing while alerts are paused, and otherwise blocks
+    /// until the sound has finished or was stopped.
+    pub fn play_sound(
+        &self,
+        display_name: &str,
+        name: &str,
+        kind: EventKind,
+    ) -> Outcome {
+        if self.is_paused() {
+            info!(
+                user = display_name,
+                sound = name,
+                "Alerts are paused, ignoring request."
+            );
+            return Outcome::Paused;
+        }
+        let trigger = Trigger {
+            user: display_name,
+            kind,
+            redeemed_at: None,
+        };
+        match self.play_for(trigger, name) {
+            Some(clip) => Outcome::Handled { clip: Some(clip) },
+            None => Outcome::Failed,
+        }
+    }
+
+    /// Plays the sound matching `reward_title` case-insensitively, blocking
+    /// until it has finished or was stopped.
+    pub fn play(
+        &self,
+        display_name: &str,
+        reward_title: &str,
+        kind: EventKind,
+    ) {
+        let trigger = Trigger {
+            user: display_name,
+            kind,
+            redeemed_at: None,
+        };
+        self.play_for(trigger, reward_title);
+    }
+
+    /// Plays the sound of a reward. Returns the clip that played, as
+    /// `Pool/clip` for pools.
+    fn play_for(&self, trigger: Trigger, reward_title: &str) -> Option<String> {
+        info!(user = trigger.user, reward = reward_title, "Redeemed.");
+
+        let Some(sound) = self.sounds.get(&reward_title.to_lowercase()) else {
+            info!(reward = reward_title, "No matching sound for reward.");
+            return None;
+        };
+        let name = &sound.name;
+        let (clip_name, path) = self.pick_clip(sound);
+        if sound.is_pool() {
+            info!(sound = %name, clip = %clip_name, "Picked clip from pool.");
+        }
+
+        let clip = match self.load_clip(path) {
+            Ok(clip) => clip,
+            Err(e) => {
+                warn!(file = ?path, "Failed to decode sound file: {}", e);
+                return None;
             }
+        };
+        if !self.play_clip(trigger, name, clip_name, Some(path), &clip) {
+            return None;
+        }
+        Some(if sound.is_pool() {
+            format!("{}/{}", name, clip_name)
         } else {
-            println!("No audio output device available.");
+            name.clone()
+        })
+    }
+
+    /// Speaks `text` with the voice of the reward, through the same mixer
+    /// as the sounds, blocking until it has finished. Returns whether it was
+    /// spoken; a message with nothing left to say counts as spoken.
+    fn speak(&self, trigger: Trigger, reward_title: &str, text: &str) -> bool {
+        let Some(tts) = &self.tts else {
+            info!(reward = reward_title, "Text-to-speech is not configured.");
+            return false;
+        };
+        let Some(text) = tts.prepare(text) else {
+            return true;
+        };
+        let voice = self
+            .settings
+            .sound(reward_title)
+            .and_then(|sound| sound.voice.as_deref());
+        match tts.synthesize(&text, voice) {
+            Ok(clip) => {
+                self.play_clip(trigger, reward_title, "tts", None, &clip)
+            }
+            Err(e) => {
+                warn!(
+                    reward = reward_title,
+                    "Failed to synthesize speech: {}", e
+                );
+                false
+            }
+        }
+    }
+
+    /// Finds the sound a viewer asked for: the sound with that name ignoring
+    /// case, else the most similar name if it reaches the match threshold.
+    pub fn resolve_input(&self, input: &str) -> Option<&str> {
+        let wanted = input.trim().to_lowercase();
+        if let Some(name) = self.find_sound(&wanted) {
+            return Some(name);
+        }
+        self.sounds
+            .iter()
+            .map(|(key, sound)| {
+                (strsim::normalized_damerau_levenshtein(&wanted, key), sound)
+            })
+            .filter(|(score, _)| *score >= self.match_threshold)
+            // Ties go to the alphabetically first name.
+            .max_by(|a, b| {
+                a.0.total_cmp(&b.0).then_with(|| b.1.name.cmp(&a.1.name))
+            })
+            .map(|(_, sound)| sound.name.as_str())
+    }
+
+    /// Handles a redemption: plays the reward's sound and, if the reward has
+    /// text-to-speech enabled, speaks the viewer's message after it or
+    /// instead of it. Rewards that take the sound from the viewer's message
+    /// play the closest matching sound instead. `redeemed_at` is when the
+    /// viewer redeemed it, to measure how long the sound took to start.
+    /// If the message cannot be spoken, a reward that speaks it instead of
+    /// the sound fails, and one that speaks it after the sound keeps the
+    /// sound's outcome.
+    pub fn redeem(
+        &self,
+        display_name: &str,
+        reward_title: &str,
+        user_input: &str,
+        kind: EventKind,
+        redeemed_at: Option<SystemTime>,
+    ) -> Outcome {
+        let trigger = Trigger {
+            user: display_name,
+            kind,
+            redeemed_at,
+        };
+        if self.is_paused() {
+            info!(
+                user = display_name,
+                reward = reward_title,
+                "Alerts are paused, ignoring redemption."
+            );
+            return Outcome::Paused;
+        }
+        let settings = self.settings.sound(reward_title);
+        if settings.is_some_and(|s| s.sound_from_input) {
+            let Some(sound) = self.resolve_input(user_input) else {
+                info!(
+                    user = display_name,
+                    reward = reward_title,
+                    input = user_input,
+                    "No sound matches the viewer's input."
+                );
+                return Outcome::NoMatch;
+            };
+            return match self.play_for(trigger, sound) {
+                Some(clip) => Outcome::Handled { clip: Some(clip) },
+                None => Outcome::Failed,
+            };
+        }
+
+        let mode = settings.and_then(|s| s.tts);
+        if mode.is_none() && self.find_sound(reward_title).is_none() {
+            info!(reward = reward_title, "No matching sound for reward.");
+            return Outcome::Skipped;
+        }
+        if mode == Some(TtsMode::Only) {
+            info!(user = display_name, reward = reward_title, "Redeemed.");
+            return if self.speak(trigger, reward_title, user_input) {
+                Outcome::Handled { clip: None }
+            } else {
+                Outcome::Failed
+            };
+        }
+        let clip = self.play_for(trigger, reward_title);
+        if mode.is_some() {
+            // The latency was measured for the sound already.
+            let trigger = Trigger {
+                redeemed_at: None,
+                ..trigger
+            };
+            self.speak(trigger, reward_title, user_input);
+        }
+        match clip {
+            Some(clip) => Outcome::Handled { clip: Some(clip) },
+            None => Outcome::Failed,
+        }
+    }
+
+    /// Plays a decoded clip of the sound `name`, blocking until it has
+    /// finished or was stopped. `path` is the file it was read from, if any.
+    /// The overlay is told when the clip starts and ends. Returns whether
+    /// the clip started.
+    fn play_clip(
+        &self,
+        trigger: Trigger,
+        name: &str,
+        clip_name: &str,
+        path: Option<&Path>,
+        clip: &Clip,
+    ) -> bool {
+        let Some(_playing) = COORDINATOR.start_playback() else {
+            info!(sound = %name, "Shutting down, not starting sound.");
+            return false;
+        };
+        let mut options = self.options_for(name, path, clip, trigger.kind);
+        if options.max_duration.is_some() {
+            info!(
+                sound = %name,
+                "Sound is longer than the limit, cutting it off."
+            );
+        }
+        let volume = options.volume;
+        let priority = self.priority_for(name);
+        // Start ducked right away if a higher priority sound is playing.
+        let top = Player::top_priority(&self.active.lock().unwrap());
+        if top.is_some_and(|top| priority < top) {
+            options.volume *= self.duck_gain;
+        }
+        let voice: Arc<dyn Voice> =
+            match self.backend.play(clip_name, clip, options) {
+                Ok(voice) => voice.into(),
+                Err(e) => {
+                    warn!(sound = %name, "Failed to play sound: {}", e);
+                    return false;
+                }
+            };
+        if let Some(latency) = trigger
+            .redeemed_at
+            .and_then(|at| SystemTime::now().duration_since(at).ok())
+        {
+            metrics::PLAYBACK_LATENCY.observe(latency);
+        }
+
+        let id = self.next_voice.fetch_add(1, Ordering::SeqCst);
+        let mut event = OverlayEvent {
+            id,
+            phase: Phase::Start,
+            user: trigger.user.to_string(),
+            sound: name.to_string(),
+            clip: clip_name.to_string(),
+            image: self.settings.sound(name).and_then(|s| s.image.clone()),
+            duration_ms: options.played_duration(clip).as_millis() as u64,
+        };
+        // Sending only fails when no overlay is connected.
+        self.events.send(event.clone()).ok();
+        {
+            let mut active = self.active.lock().unwrap();
+            active.push(ActiveVoice {
+                id,
+                sound: name.to_string(),
+                priority,
+                volume,
+                started: Instant::now(),
+                voice: Arc::clone(&voice),
+            });
+            self.remix(&active);
+        }
+        // Block until the sound finishes playing or is stopped.
+        voice.wait();
+        {
+            let mut active = self.active.lock().unwrap();
+            active.retain(|a| a.id != id);
+            self.remix(&active);
+        }
+        event.phase = Phase::End;
+        self.events.send(event).ok();
+        true
+    }
+}
+
+/// Player used for redemptions, built once from the config.
+static PLAYER: Lazy<Player> = Lazy::new(Player::from_env);
+
+/// The redemption player, for the control API.
+pub fn player() -> &'static Player {
+    &PLAYER
+}
+
+/// Decodes and caches the sounds of the redemption player ahead of time.
+pub fn prewarm() -> Vec<String> {
+    PLAYER.prewarm()
+}
+
+/// Returns the available sound names in alphabetical order.
+pub fn sound_names() -> Vec<String> {
+    PLAYER.sound_names()
+}
+
+/// Stops the most recently started sound of the redemption player.
+pub fn skip_current() -> Option<String> {
+    PLAYER.skip_current()
+}
+
+/// Stops every sound of the redemption player.
+pub fn stop_all() -> usize {
+    PLAYER.stop_all()
+}
+
+/// Skips the current sound on SIGUSR1 and stops all sounds on SIGUSR2.
+/// Never returns; on platforms without these signals it waits forever.
+pub async fn listen_for_control_signals() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut skip), Ok(mut stop)) = (
+            signal(SignalKind::user_defined1()),
+            signal(SignalKind::user_defined2()),
+        ) else {
+            warn!("Failed to listen for control signals.");
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::select! {
+                _ = skip.recv() => {
+                    tokio::task::spawn_blocking(skip_current);
+                }
+                _ = stop.recv() => {
+                    tokio::task::spawn_blocking(stop_all);
+                }
+            }
         }
-    } else {
-        println!("No matching sound for reward: {}", reward_title);
     }
+
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await
+}
+
+/// Plays a sound locally, routed like a test event.
+pub fn play_test_sound(name: &str) {
+    PLAYER.play("local test", name, EventKind::Test);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio::tests::write_tone;
+    use crate::audio::NullBackend;
     use rand::seq::IndexedRandom;
+    use std::sync::Arc;
     use std::thread;
     use std::time::Duration;
 
-    /// This test assumes that a folder named `sounds` exists at the project
-    /// root and contains at least one valid MP3 file. It locks the sound list
-    /// before using methods like `is_empty()` and `choose()`. Then it spawns
-    /// 10 threads that each call `play_sound_for_redemption` with a short
-    /// delay between spawns to force overlapping playback.
+    /// Fills a temporary sounds directory with short generated tones.
+    fn sounds_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, millis) in [("Boom", 100), ("Honk", 200), ("Ding", 300)] {
+            let path = dir.path().join(format!("{}.wav", name));
+            write_tone(&path, Duration::from_millis(millis));
+        }
+        dir
+    }
+
+    /// Spawns 10 threads that each call `play` for a random sound, playing
+    /// through the null backend so no audio hardware is needed, and checks
+    /// that every clip was played with its full duration.
     #[test]
     fn test_overlapping_playback() {
-        {
-            let sound_list = SOUND_LIST.lock().unwrap();
-            assert!(
-                !sound_list.is_empty(),
-                "Sound list is empty. Please ensure that the sounds/
-                 folder contains at least one .mp3 file."
-            );
-        }
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let player = Arc::new(Player::new(
+            dir.path(),
+            Box::new(backend.clone()),
+            Settings::default(),
+        ));
+        let sound_list = player.sound_names();
+        assert_eq!(sound_list, vec!["Boom", "Ding", "Honk"]);
 
         let mut handles = Vec::new();
         for i in 0..10 {
             let display_name = format!("TestUser{}", i);
             // Choose a random sound from the sound list.
             let chosen_sound = {
-                let sound_list = SOUND_LIST.lock().unwrap();
                 let mut rng = rand::rng();
                 sound_list.choose(&mut rng).unwrap().clone()
             };
-            // For testing, we assume the reward title exactly equals the name.
-            let reward_title = chosen_sound.clone();
+            // Reward titles match regardless of case.
+            let reward_title = chosen_sound.to_uppercase();
 
+            let player = Arc::clone(&player);
             let handle = thread::spawn(move || {
-                play_sound_for_redemption(&display_name, &reward_title);
+                player.play(
+                    &display_name,
+                    &reward_title,
+                    EventKind::Redemption,
+                );
             });
             handles.push(handle);
         }
@@ -109,7 +1053,418 @@ mod tests {
         for handle in handles {
             handle.join().unwrap();
         }
-        // Allow extra time for the sounds to play.
-        thread::sleep(Duration::from_secs(3));
+
+        let played = backend.played();
+        assert_eq!(played.len(), 10);
+        for clip in played {
+            let expected = match clip.name.as_str() {
+                "Boom" => 100,
+                "Honk" => 200,
+                _ => 300,
+            };
+            assert_eq!(clip.duration, Duration::from_millis(expected));
+        }
+    }
+
+    #[test]
+    fn test_unmatched_reward_plays_nothing() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let player = Player::new(
+            dir.path(),
+            Box::new(backend.clone()),
+            Settings::default(),
+        );
+        player.play("TestUser", "NoSuchSound", EventKind::Redemption);
+        assert!(backend.played().is_empty());
+    }
+
+    #[test]
+    fn test_routes_sounds_and_event_types_to_devices() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "sounds": { "boom": { "device": "Cable" } },
+                "devices": { "redemption": "Speakers" }
+            }"#,
+        )
+        .unwrap();
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings);
+
+        player.play("TestUser", "Boom", EventKind::Redemption);
+        player.play("TestUser", "Honk", EventKind::Redemption);
+        player.play("TestUser", "Honk", EventKind::Test);
+
+        let devices: Vec<Option<String>> = backend
+            .played()
+            .into_iter()
+            .map(|clip| clip.device)
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                Some("Cable".to_string()),
+                Some("Speakers".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_applies_master_gain_and_normalization() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Boom": { "gain": 0.5 } } }"#,
+        )
+        .unwrap();
+        let cache = LoudnessCache::load(dir.path().join("loudness.json"));
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings)
+                .with_master_volume(0.8)
+                .with_normalization(-20.0, cache);
+
+        player.play("TestUser", "Boom", EventKind::Redemption);
+        player.play("TestUser", "Honk", EventKind::Redemption);
+
+        // The test tones have an RMS of 0.5 / sqrt(2), so reaching -20 dBFS
+        // (0.1 linear) takes a gain of about 0.2828.
+        let volumes: Vec<f32> = backend
+            .played()
+            .into_iter()
+            .map(|clip| clip.volume)
+            .collect();
+        assert!((volumes[0] - 0.8 * 0.5 * 0.2828).abs() < 1e-3);
+        assert!((volumes[1] - 0.8 * 0.2828).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_prewarm_reports_broken_files_and_serves_from_memory() {
+        let dir = sounds_dir();
+        std::fs::write(dir.path().join("Broken.mp3"), b"not audio").unwrap();
+        let backend = NullBackend::new();
+        let player = Player::new(
+            dir.path(),
+            Box::new(backend.clone()),
+            Settings::default(),
+        );
+
+        assert_eq!(player.prewarm(), vec!["Broken"]);
+        let outcome = player.redeem(
+            "TestUser",
+            "Broken",
+            "",
+            EventKind::Redemption,
+            None,
+        );
+        assert_eq!(outcome, Outcome::Failed);
+
+        // Cached clips keep playing even once the file is gone.
+        std::fs::remove_file(dir.path().join("Boom.wav")).unwrap();
+        player.play("TestUser", "Boom", EventKind::Redemption);
+        assert_eq!(backend.played().len(), 1);
+    }
+
+    #[test]
+    fn test_pool_directory_plays_clips_by_strategy() {
+        let dir = sounds_dir();
+        let pool = dir.path().join("Memes");
+        std::fs::create_dir(&pool).unwrap();
+        for name in ["b", "a", "c"] {
+            write_tone(
+                &pool.join(format!("{}.wav", name)),
+                Duration::from_millis(50),
+            );
+        }
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "memes": { "strategy": "round_robin" } } }"#,
+        )
+        .unwrap();
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings)
+                .with_seed(1);
+
+        assert!(player.sound_names().contains(&"Memes".to_string()));
+        for _ in 0..4 {
+            player.play("TestUser", "memes", EventKind::Redemption);
+        }
+        let names: Vec<String> =
+            backend.played().into_iter().map(|clip| clip.name).collect();
+        assert_eq!(names, vec!["a", "b", "c", "a"]);
+
+        // Redemptions report the clip that played, not the pool.
+        let outcome =
+            player.redeem("TestUser", "memes", "", EventKind::Redemption, None);
+        assert_eq!(
+            outcome,
+            Outcome::Handled {
+                clip: Some("Memes/b".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn test_cuts_off_long_clips_and_stops_on_request() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Ding": { "fade_in_ms": 20 } } }"#,
+        )
+        .unwrap();
+        let player = Arc::new(
+            Player::new(dir.path(), Box::new(backend.clone()), settings)
+                .with_max_duration(Duration::from_millis(150)),
+        );
+
+        player.play("TestUser", "Boom", EventKind::Redemption);
+        assert_eq!(backend.played()[0].duration, Duration::from_millis(100));
+
+        let playing = {
+            let player = Arc::clone(&player);
+            thread::spawn(move || {
+                player.play("TestUser", "Ding", EventKind::Redemption)
+            })
+        };
+        while player.active.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(player.skip_current().as_deref(), Some("Ding"));
+        playing.join().unwrap();
+
+        assert_eq!(backend.played()[1].duration, Duration::from_millis(150));
+        assert!(player.active.lock().unwrap().is_empty());
+        assert_eq!(player.stop_all(), 0);
+    }
+
+    #[test]
+    fn test_ducks_lower_priority_sounds_while_priority_sound_plays() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Ding": { "priority": 10 } } }"#,
+        )
+        .unwrap();
+        let player = Arc::new(
+            Player::new(dir.path(), Box::new(backend.clone()), settings)
+                .with_ducking(20.0),
+        );
+        let volume_of = |sound: &str| {
+            let active = player.active.lock().unwrap();
+            let found = active.iter().find(|a| a.sound == sound);
+            found.map(|a| a.voice.volume())
+        };
+
+        let spawn = |sound: &'static str| {
+            let player = Arc::clone(&player);
+            thread::spawn(move || {
+                player.play("TestUser", sound, EventKind::Redemption)
+            })
+        };
+        let honk = spawn("Honk");
+        while volume_of("Honk").is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(volume_of("Honk"), Some(1.0));
+
+        // Ding outranks Honk, so Honk drops by 20 dB while Ding plays.
+        let ding = spawn("Ding");
+        while volume_of("Ding").is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!((volume_of("Honk").unwrap() - 0.1).abs() < 1e-6);
+        assert_eq!(volume_of("Ding"), Some(1.0));
+
+        // Once Ding is stopped, Honk is back at full volume.
+        player.skip_current();
+        ding.join().unwrap();
+        assert_eq!(volume_of("Honk"), Some(1.0));
+        honk.join().unwrap();
+    }
+
+    #[test]
+    fn test_picks_sound_from_user_input_by_similarity() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Any Sound": { "sound_from_input": true } } }"#,
+        )
+        .unwrap();
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings)
+                .with_match_threshold(0.7);
+
+        assert_eq!(player.resolve_input(" DING "), Some("Ding"));
+        // One typo in four letters is 0.75 similar, two are too many.
+        assert_eq!(player.resolve_input("hnok"), Some("Honk"));
+        assert_eq!(player.resolve_input("hnkk"), None);
+
+        let outcome = player.redeem(
+            "TestUser",
+            "any sound",
+            "bom",
+            EventKind::Redemption,
+            None,
+        );
+        assert_eq!(
+            outcome,
+            Outcome::Handled {
+                clip: Some("Boom".to_string())
+            }
+        );
+        let outcome = player.redeem(
+            "TestUser",
+            "Any Sound",
+            "something else",
+            EventKind::Redemption,
+            None,
+        );
+        assert_eq!(outcome, Outcome::NoMatch);
+        let outcome =
+            player.redeem("TestUser", "Other", "", EventKind::Redemption, None);
+        assert_eq!(outcome, Outcome::Skipped);
+
+        let names: Vec<String> =
+            backend.played().into_iter().map(|clip| clip.name).collect();
+        assert_eq!(names, vec!["Boom"]);
+    }
+
+    #[test]
+    fn test_plays_sounds_by_name_ignoring_reward_settings() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Boom": { "tts": "only" },
+                             "Honk": { "sound_from_input": true } } }"#,
+        )
+        .unwrap();
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings);
+
+        player.set_paused(true);
+        assert_eq!(
+            player.play_sound("TestUser", "Boom", EventKind::Chat),
+            Outcome::Paused
+        );
+        player.set_paused(false);
+        for name in ["Boom", "Honk"] {
+            assert_eq!(
+                player.play_sound("TestUser", name, EventKind::Chat),
+                Outcome::Handled {
+                    clip: Some(name.to_string())
+                }
+            );
+        }
+        let names: Vec<String> =
+            backend.played().into_iter().map(|clip| clip.name).collect();
+        assert_eq!(names, vec!["Boom", "Honk"]);
+    }
+
+    #[test]
+    fn test_announces_clip_start_and_end_to_overlay() {
+        let dir = sounds_dir();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Boom": { "image": "boom.gif" } } }"#,
+        )
+        .unwrap();
+        let player =
+            Player::new(dir.path(), Box::new(NullBackend::new()), settings);
+        let mut events = player.subscribe();
+
+        player.play("TestUser", "boom", EventKind::Redemption);
+
+        let start = events.try_recv().unwrap();
+        let end = events.try_recv().unwrap();
+        assert_eq!(start.phase, Phase::Start);
+        assert_eq!(start.user, "TestUser");
+        assert_eq!(start.sound, "Boom");
+        assert_eq!(start.image.as_deref(), Some("boom.gif"));
+        assert_eq!(start.duration_ms, 100);
+        assert_eq!(end.phase, Phase::End);
+        assert_eq!(end.id, start.id);
+    }
+
+    #[test]
+    fn test_speaks_user_input_after_or_instead_of_sound() {
+        use crate::tts::tests::FakeEngine;
+        use crate::tts::Tts;
+
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "sounds": {
+                    "Boom": { "tts": "after", "voice": "en-gb" },
+                    "Say It": { "tts": "only" }
+                },
+                "tts": { "blocked_words": ["heck"] }
+            }"#,
+        )
+        .unwrap();
+        let engine = FakeEngine::default();
+        let tts = Tts::new(Box::new(engine.clone()), &settings.tts);
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings)
+                .with_tts(tts);
+
+        player.redeem(
+            "TestUser",
+            "Boom",
+            "what the heck",
+            EventKind::Redemption,
+            None,
+        );
+        let outcome = player.redeem(
+            "TestUser",
+            "say it",
+            "hi",
+            EventKind::Redemption,
+            None,
+        );
+        assert_eq!(outcome, Outcome::Handled { clip: None });
+        // Rewards without text-to-speech ignore the message.
+        player.redeem(
+            "TestUser",
+            "Honk",
+            "ignored",
+            EventKind::Redemption,
+            None,
+        );
+
+        let names: Vec<String> =
+            backend.played().into_iter().map(|clip| clip.name).collect();
+        assert_eq!(names, vec!["Boom", "tts", "tts", "Honk"]);
+        assert_eq!(
+            *engine.spoken.lock().unwrap(),
+            vec![
+                ("what the bleep".to_string(), Some("en-gb".to_string())),
+                ("hi".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unspoken_message_fails_the_redemption() {
+        let dir = sounds_dir();
+        let backend = NullBackend::new();
+        let settings: Settings = serde_json::from_str(
+            r#"{ "sounds": { "Say It": { "tts": "only" } } }"#,
+        )
+        .unwrap();
+        // No text-to-speech engine is configured.
+        let player =
+            Player::new(dir.path(), Box::new(backend.clone()), settings);
+
+        let outcome = player.redeem(
+            "TestUser",
+            "Say It",
+            "hi",
+            EventKind::Redemption,
+            None,
+        );
+        assert_eq!(outcome, Outcome::Failed);
+        assert!(backend.played().is_empty());
     }
 }
@@ -0,0 +1,296 @@
+use crate::audio::Clip;
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+/// Whether a reward speaks its user input, and when.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TtsMode {
+    /// Play the reward's sound, then speak the message.
+    After,
+    /// Only speak the message.
+    Only,
+}
+
+/// Options of the text-to-speech stage in `settings.json`.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct TtsSettings {
+    /// Words replaced by "bleep" before the message is spoken, matched
+    /// case-insensitively.
+    pub blocked_words: Vec<String>,
+}
+
+/// A local speech synthesizer.
+pub trait Engine: Send + Sync {
+    /// Speaks `text` into a WAV file at `out`. `voice` is engine specific,
+    /// `None` uses the engine's default.
+    fn synthesize(
+        &self,
+        text: &str,
+        voice: Option<&str>,
+        out: &Path,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// Runs a synthesizer that reads the text from stdin. The text is never put
+/// on the command line, so messages cannot inject options.
+fn run_with_stdin(
+    mut command: Command,
+    text: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// espeak-ng, with voices named like `en-us`.
+pub struct Espeak {
+    pub program: String,
+}
+
+impl Engine for Espeak {
+    fn synthesize(
+        &self,
+        text: &str,
+        voice: Option<&str>,
+        out: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut command = Command::new(&self.program);
+        command.arg("--stdin").arg("-w").arg(out);
+        if let Some(voice) = voice {
+            command.arg("-v").arg(voice);
+        }
+        run_with_stdin(command, text)
+    }
+}
+
+/// Piper, where the voice is the path of an `.onnx` voice model.
+pub struct Piper {
+    pub program: String,
+}
+
+impl Engine for Piper {
+    fn synthesize(
+        &self,
+        text: &str,
+        voice: Option<&str>,
+        out: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let voice = voice.ok_or("piper needs a voice model (TTS_VOICE)")?;
+        let mut command = Command::new(&self.program);
+        command
+            .arg("--model")
+            .arg(voice)
+            .arg("--output_file")
+            .arg(out);
+        run_with_stdin(command, text)
+    }
+}
+
+/// Default longest message that is spoken, in characters.
+const DEFAULT_MAX_CHARS: usize = 200;
+
+/// Turns redemption messages into clips.
+pub struct Tts {
+    engine: Box<dyn Engine>,
+    default_voice: Option<String>,
+    max_chars: usize,
+    blocked_words: Vec<String>,
+    counter: AtomicU64,
+}
+
+impl Tts {
+    pub fn new(engine: Box<dyn Engine>, settings: &TtsSettings) -> Tts {
+        Tts {
+            engine,
+            default_voice: None,
+            max_chars: DEFAULT_MAX_CHARS,
+            blocked_words: settings
+                .blocked_words
+                .iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Uses `voice` for rewards that do not pick their own.
+    pub fn with_default_voice(mut self, voice: String) -> Tts {
+        self.default_voice = Some(voice);
+        self
+    }
+
+    /// Cuts messages off after `max_chars` characters.
+    pub fn with_max_chars(mut self, max_chars: usize) -> Tts {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /// Builds the engine selected by `TTS_ENGINE` (`espeak` or `piper`, run
+    /// as `TTS_COMMAND` if set), with `TTS_VOICE` and `TTS_MAX_CHARS`.
+    /// Returns `None` when text-to-speech is not configured.
+    pub fn from_env(settings: &TtsSettings) -> Option<Tts> {
+        let program = env::var("TTS_COMMAND").ok();
+        let engine: Box<dyn Engine> =
+            match env::var("TTS_ENGINE").ok()?.as_str() {
+                "espeak" => Box::new(Espeak {
+                    program: program.unwrap_or_else(|| "espeak-ng".to_string()),
+                }),
+                "piper" => Box::new(Piper {
+                    program: program.unwrap_or_else(|| "piper".to_string()),
+                }),
+                other => {
+                    warn!(engine = other, "Unknown TTS_ENGINE, TTS disabled.");
+                    return None;
+                }
+            };
+        let mut tts = Tts::new(engine, settings);
+        if let Ok(voice) = env::var("TTS_VOICE") {
+            tts = tts.with_default_voice(voice);
+        }
+        if let Some(max) = env::var("TTS_MAX_CHARS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+        {
+            tts = tts.with_max_chars(max);
+        }
+        Some(tts)
+    }
+
+    /// Cleans up a message before it is spoken: blocked words are replaced,
+    /// whitespace is collapsed and the text is cut to the maximum length.
+    /// Returns `None` if nothing is left to say.
+    pub fn prepare(&self, text: &str) -> Option<String> {
+        let words: Vec<&str> = text
+            .split_whitespace()
+            .map(|word| {
+                let bare = word
+                    .trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase();
+                if self.blocked_words.contains(&bare) {
+                    "bleep"
+                } else {
+                    word
+                }
+            })
+            .collect();
+        let text: String =
+            words.join(" ").chars().take(self.max_chars).collect();
+        Some(text).filter(|text| !text.is_empty())
+    }
+
+    /// Speaks `text` with `voice`, or the default voice, and decodes the
+    /// result.
+    pub fn synthesize(
+        &self,
+        text: &str,
+        voice: Option<&str>,
+    ) -> Result<Clip, Box<dyn Error>> {
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        let out: PathBuf = env::temp_dir().join(format!(
+            "twitch-soundbot-tts-{}-{}.wav",
+            std::process::id(),
+            n
+        ));
+        let voice = voice.or(self.default_voice.as_deref());
+        let result = self
+            .engine
+            .synthesize(text, voice, &out)
+            .and_then(|()| Clip::decode(&out));
+        fs::remove_file(&out).ok();
+        result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::audio::tests::write_tone;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Messages and voices an engine was asked to speak.
+    pub type Spoken = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Engine that records what it was asked to say and writes a tone of
+    /// 10 ms per character.
+    #[derive(Clone, Default)]
+    pub struct FakeEngine {
+        pub spoken: Spoken,
+    }
+
+    impl Engine for FakeEngine {
+        fn synthesize(
+            &self,
+            text: &str,
+            voice: Option<&str>,
+            out: &Path,
+        ) -> Result<(), Box<dyn Error>> {
+            self.spoken
+                .lock()
+                .unwrap()
+                .push((text.to_string(), voice.map(str::to_string)));
+            write_tone(out, Duration::from_millis(10 * text.len() as u64));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_prepare_filters_words_and_limits_length() {
+        let settings = TtsSettings {
+            blocked_words: vec!["Darn".to_string()],
+        };
+        let tts = Tts::new(Box::new(FakeEngine::default()), &settings)
+            .with_max_chars(20);
+
+        assert_eq!(
+            tts.prepare("  well,   DARN!  that was loud ").as_deref(),
+            Some("well, bleep that was")
+        );
+        assert_eq!(tts.prepare(" \n "), None);
+    }
+
+    #[test]
+    fn test_synthesize_uses_default_voice_and_cleans_up() {
+        let engine = FakeEngine::default();
+        let tts = Tts::new(Box::new(engine.clone()), &TtsSettings::default())
+            .with_default_voice("en-us".to_string());
+
+        let clip = tts.synthesize("hello", None).unwrap();
+        assert_eq!(clip.duration(), Duration::from_millis(50));
+        tts.synthesize("hi", Some("de")).unwrap();
+
+        let voices: Vec<Option<String>> = engine
+            .spoken
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, voice)| voice.clone())
+            .collect();
+        assert_eq!(
+            voices,
+            vec![Some("en-us".to_string()), Some("de".to_string())]
+        );
+    }
+}